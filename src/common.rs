@@ -1,5 +1,20 @@
+pub mod address;
+pub mod chunks;
 pub mod clipboard;
+#[cfg(unix)]
+pub mod control;
+pub mod discovery;
 pub mod hotkey;
-pub mod message;
+pub mod identity;
+pub mod logging;
+pub mod membership;
 pub mod packer;
+pub mod partial;
+pub mod policy;
+pub mod progress;
+pub mod secret;
+pub mod throttle;
+pub mod tls;
 pub mod uniclip;
+#[cfg(target_os = "linux")]
+pub mod xorg;
@@ -0,0 +1,120 @@
+use std::net::{IpAddr, SocketAddr};
+
+// 解析 host, host:port, IPv6 地址, [IPv6]:port, 未指定端口时返回 None
+pub fn parse_remote(s: &str) -> Result<(String, Option<u16>), String> {
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("Invalid port \"{}\"", port))
+    };
+
+    if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = match rest.split_once(']') {
+            Some(res) => res,
+            None => return Err(format!("Invalid address \"{}\"", s)),
+        };
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+            return Err(format!("Invalid IPv6 address \"{}\"", host));
+        }
+        return match rest {
+            "" => Ok((host.to_string(), None)),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => Ok((host.to_string(), Some(parse_port(port)?))),
+                None => Err(format!("Invalid address \"{}\"", s)),
+            },
+        };
+    }
+
+    // 不带方括号的 IPv6 地址没有端口
+    if s.parse::<IpAddr>().is_ok() {
+        return Ok((s.to_string(), None));
+    }
+
+    match s.rsplit_once(':') {
+        Some((host, _)) if host.contains(':') => Err(format!("Invalid address \"{}\"", s)),
+        Some((host, port)) if !host.is_empty() => Ok((host.to_string(), Some(parse_port(port)?))),
+        Some(_) => Err(format!("Invalid address \"{}\"", s)),
+        None if !s.is_empty() => Ok((s.to_string(), None)),
+        None => Err("Empty address".to_string()),
+    }
+}
+
+pub fn parse_listen(s: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let (host, port) = parse_remote(s)?;
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => return Err(format!("Invalid listen address \"{}\"", host)),
+    };
+    Ok(SocketAddr::new(ip, port.unwrap_or(default_port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(host: &str, port: Option<u16>) -> Result<(String, Option<u16>), String> {
+        Ok((host.to_string(), port))
+    }
+
+    #[test]
+    fn remote_with_and_without_port() {
+        assert_eq!(parse_remote("example.com"), remote("example.com", None));
+        assert_eq!(
+            parse_remote("example.com:10500"),
+            remote("example.com", Some(10500))
+        );
+        assert_eq!(parse_remote("10.0.0.2"), remote("10.0.0.2", None));
+        assert_eq!(parse_remote("10.0.0.2:80"), remote("10.0.0.2", Some(80)));
+        assert!(parse_remote("").is_err());
+        assert!(parse_remote(":10500").is_err());
+    }
+
+    #[test]
+    fn remote_ipv6() {
+        assert_eq!(parse_remote("[::1]:10500"), remote("::1", Some(10500)));
+        assert_eq!(parse_remote("[::1]"), remote("::1", None));
+        assert_eq!(parse_remote("fe80::1"), remote("fe80::1", None));
+
+        // 方括号内必须是 IPv6 地址, 端口前必须有 :
+        assert!(parse_remote("[::1").is_err());
+        assert!(parse_remote("[example.com]:10500").is_err());
+        assert!(parse_remote("[::1]10500").is_err());
+        // 不带方括号时无法区分端口
+        assert!(parse_remote("fe80::1:x").is_err());
+    }
+
+    #[test]
+    fn remote_bad_port() {
+        assert!(parse_remote("example.com:").is_err());
+        assert!(parse_remote("example.com:65536").is_err());
+        assert!(parse_remote("example.com:-1").is_err());
+        assert!(parse_remote("[::1]:port").is_err());
+        assert!(parse_remote("[::1]:").is_err());
+    }
+
+    #[test]
+    fn listen_addresses() {
+        assert_eq!(
+            parse_listen("0.0.0.0", 10500),
+            Ok("0.0.0.0:10500".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen("127.0.0.1:11000", 10500),
+            Ok("127.0.0.1:11000".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen("[::]", 10500),
+            Ok("[::]:10500".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen("::1", 10500),
+            Ok("[::1]:10500".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen("[::1]:11000", 10500),
+            Ok("[::1]:11000".parse().unwrap())
+        );
+        // 监听地址必须是 IP
+        assert!(parse_listen("localhost", 10500).is_err());
+        assert!(parse_listen("0.0.0.0:99999", 10500).is_err());
+    }
+}
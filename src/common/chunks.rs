@@ -0,0 +1,132 @@
+use super::packer;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use tracing::{debug, error};
+
+// 按 SHA-256 保存收到的分块, 重复发送相同的内容时只传输缺少的分块.
+// 每种格式的内容分别分块, 条目信息单独发送, 内容相同的格式在不同条目中分块相同
+
+// 缓存的总大小, 超过时删除最早的分块
+const CACHE_LIMIT: u64 = 256 * 1024 * 1024;
+
+static SIZE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref DIR: RwLock<PathBuf> = RwLock::new(PathBuf::new());
+    // 正在进行的传输需要的分块及其引用数, 不会被删除
+    static ref PINNED: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+// 释放时解除固定
+pub struct Pin {
+    hashes: Vec<String>,
+}
+
+// 固定传输用到的分块, 包括传输中才写入缓存的分块
+pub fn pin(hashes: &[String]) -> Pin {
+    let mut hashes = hashes.to_vec();
+    hashes.sort();
+    hashes.dedup();
+    let mut pinned = PINNED.lock().unwrap();
+    for hash in hashes.iter() {
+        *pinned.entry(hash.clone()).or_default() += 1;
+    }
+    Pin { hashes }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pinned = PINNED.lock().unwrap();
+        for hash in self.hashes.iter() {
+            if let Some(count) = pinned.get_mut(hash) {
+                *count -= 1;
+                if *count == 0 {
+                    pinned.remove(hash);
+                }
+            }
+        }
+    }
+}
+
+pub fn init(dir: PathBuf) {
+    *DIR.write().unwrap() = dir;
+    evict();
+}
+
+// hash 由对方发送, 只接受 SHA-256 的十六进制形式, 防止写到目录之外
+fn path(hash: &str) -> Option<PathBuf> {
+    if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(DIR.read().unwrap().join(hash))
+}
+
+pub fn has(hash: &str) -> bool {
+    path(hash).is_some_and(|path| path.is_file())
+}
+
+// 内容与 hash 不符的分块视为不存在
+pub fn get(hash: &str) -> Option<Vec<u8>> {
+    let path = path(hash)?;
+    let data = fs::read(&path).ok()?;
+    if packer::hash(&data[..]) != hash {
+        let _ = fs::remove_file(&path);
+        return None;
+    }
+    Some(data)
+}
+
+pub fn put(hash: &str, data: &[u8]) {
+    let path = match path(hash) {
+        Some(path) => path,
+        None => return,
+    };
+    if path.is_file() {
+        return;
+    }
+    let res = fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(&path, data));
+    if let Err(error) = res {
+        error!("{}: {}", path.display(), error);
+        return;
+    }
+    if SIZE.fetch_add(data.len() as u64, Ordering::SeqCst) + data.len() as u64 > CACHE_LIMIT {
+        evict();
+    }
+}
+
+// 重新统计缓存大小, 超过限制时按修改时间删除最早的未固定的分块.
+// 删除期间持有锁, 防止刚固定的分块被删除
+fn evict() {
+    let pinned = PINNED.lock().unwrap();
+    let dir = DIR.read().unwrap().clone();
+    let mut files: Vec<_> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                Some((meta.modified().ok()?, meta.len(), entry.path()))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in files.iter() {
+        if size <= CACHE_LIMIT {
+            break;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if pinned.contains_key(name.as_ref()) {
+            continue;
+        }
+        debug!("Remove cached chunk {}", path.display());
+        if fs::remove_file(path).is_ok() {
+            size -= len;
+        }
+    }
+    SIZE.store(size, Ordering::SeqCst);
+}
@@ -1,25 +1,337 @@
-use super::message;
-use arboard::Clipboard;
+use super::super::datatype::{
+    ClipboardEntry, HeadlessConfig, MIME_HTML, MIME_PASSWORD_HINT, MIME_PNG, MIME_RTF, MIME_TEXT,
+};
+use super::packer;
+use arboard::{Clipboard, ImageData};
+use image::codecs::png::PngEncoder;
+use image::ColorType;
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use tracing::{debug, error, warn};
 
-pub fn get() -> String {
-    let mut clipboard = Clipboard::new().unwrap();
+#[cfg(target_os = "linux")]
+mod selection;
+
+static HEADLESS: AtomicBool = AtomicBool::new(false);
+
+// 获取只收到条目信息的内容, 返回粘贴时提供的各格式
+pub type Fetch = Box<dyn FnOnce() -> Result<Vec<(String, Vec<u8>)>, String> + Send>;
+
+lazy_static! {
+    // 无头模式下代替系统剪贴板
+    static ref BUFFER: Mutex<Option<ClipboardEntry>> = Mutex::new(None);
+    // 无头模式下剪贴板中只有条目信息时, 粘贴前获取内容
+    static ref OFFERED: Mutex<Option<Fetch>> = Mutex::new(None);
+    static ref SINK: RwLock<HeadlessConfig> = RwLock::new(HeadlessConfig::default());
+    // 最近写入的条目的 hash 和写入后读回的内容的 hash, 只提供了条目信息时没有内容.
+    // 文件只写入路径, 图片重新编码, 只接收部分格式时读回的内容都和条目不同
+    static ref WRITTEN: Mutex<Option<(String, Option<String>)>> = Mutex::new(None);
+}
+
+fn open() -> Option<Clipboard> {
+    match Clipboard::new() {
+        Ok(clipboard) => Some(clipboard),
+        Err(error) => {
+            error!("{}", error);
+            None
+        }
+    }
+}
+
+// 是否可以访问系统剪贴板
+pub fn available() -> bool {
+    Clipboard::new().is_ok()
+}
+
+pub fn set_headless(config: HeadlessConfig) {
+    *SINK.write().unwrap() = config;
+    HEADLESS.store(true, Ordering::SeqCst);
+}
+
+pub fn is_headless() -> bool {
+    HEADLESS.load(Ordering::SeqCst)
+}
+
+// 读取当前内容, 无头模式下读取缓冲区
+pub fn get(origin: &str) -> ClipboardEntry {
+    if is_headless() {
+        let buffer = BUFFER.lock().unwrap().clone();
+        return buffer.unwrap_or_else(|| ClipboardEntry::from_text(origin, String::new()));
+    }
+    let mut clipboard = match open() {
+        Some(clipboard) => clipboard,
+        None => return ClipboardEntry::from_text(origin, String::new()),
+    };
     let res = clipboard.get_text();
-    match res {
+    let text = match res {
         Ok(s) => s,
         Err(error) => {
-            message::error(format!("{}", error));
-            "".to_string()
+            // 剪贴板中只有图片时读取图片
+            if let Some(image) = get_image(&mut clipboard) {
+                return ClipboardEntry::new(origin, vec![(MIME_PNG.to_string(), image)]);
+            }
+            error!("{}", error);
+            return ClipboardEntry::from_text(origin, String::new());
+        }
+    };
+    // 有 HTML 或 RTF 时和纯文本一起发送
+    let mut formats = match read_formats(&[MIME_HTML, MIME_RTF, MIME_PASSWORD_HINT]) {
+        Ok(formats) => formats,
+        Err(error) => {
+            debug!("Unable to read rich text: {}", error);
+            Vec::new()
         }
+    };
+    let mut sensitive = false;
+    formats.retain(|(mime, data)| {
+        if mime != MIME_PASSWORD_HINT {
+            return true;
+        }
+        sensitive = data.as_slice() == b"secret";
+        false
+    });
+    formats.insert(0, (MIME_TEXT.to_string(), text.into_bytes()));
+    ClipboardEntry {
+        sensitive,
+        ..ClipboardEntry::new(origin, formats)
     }
 }
 
+// 读取图片并编码为 png
+fn get_image(clipboard: &mut Clipboard) -> Option<Vec<u8>> {
+    let image = clipboard.get_image().ok()?;
+    let mut data = Vec::new();
+    let res = PngEncoder::new(&mut data).encode(
+        &image.bytes,
+        image.width as u32,
+        image.height as u32,
+        ColorType::Rgba8,
+    );
+    match res {
+        Ok(_) => Some(data),
+        Err(error) => {
+            error!("{}", error);
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_formats(mimes: &[&str]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    selection::read(mimes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_formats(_mimes: &[&str]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "linux")]
+fn write_formats(formats: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+    selection::write(formats)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_formats(_formats: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+    Err("Rich text is only supported on X11".to_string())
+}
+
+// 剪贴板中只写入条目信息, 粘贴时才获取内容, 不支持时返回 Err
+pub fn offer(hash: &str, mimes: Vec<String>, fetch: Fetch) -> Result<(), String> {
+    if is_headless() {
+        *OFFERED.lock().unwrap() = Some(fetch);
+    } else {
+        offer_formats(mimes, fetch)?;
+    }
+    *WRITTEN.lock().unwrap() = Some((hash.to_string(), None));
+    Ok(())
+}
+
+// 剪贴板中仍是只有条目信息的条目时返回它的 hash.
+// 这时读取剪贴板会获取内容, 内部比较剪贴板时使用 hash
+pub fn offered() -> Option<String> {
+    let pending = match is_headless() {
+        true => OFFERED.lock().unwrap().is_some(),
+        false => owns_selection(),
+    };
+    match WRITTEN.lock().unwrap().as_ref() {
+        Some((hash, None)) if pending => Some(hash.clone()),
+        _ => None,
+    }
+}
+
+// 无头模式下获取剪贴板中只有条目信息的内容, 收到后写入缓冲区, 失败时不再重试
+pub fn fetch_offered() -> Result<(), String> {
+    let fetch = OFFERED.lock().unwrap().take();
+    match fetch {
+        Some(fetch) => fetch().map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn offer_formats(mimes: Vec<String>, fetch: Fetch) -> Result<(), String> {
+    selection::offer(mimes, fetch)
+}
+
+#[cfg(target_os = "linux")]
+fn owns_selection() -> bool {
+    selection::owned()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn owns_selection() -> bool {
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+fn offer_formats(_mimes: Vec<String>, _fetch: Fetch) -> Result<(), String> {
+    Err("Offered updates are only supported on X11".to_string())
+}
+
 pub fn set(s: String) {
-    let mut clipboard = Clipboard::new().unwrap();
-    let res =  clipboard.set_text(s);
+    let mut clipboard = match open() {
+        Some(clipboard) => clipboard,
+        None => return,
+    };
+    let res = clipboard.set_text(s);
     match res {
         Ok(_) => (),
         Err(error) => {
-            message::error(format!("{}", error));
+            error!("{}", error);
+        }
+    }
+}
+
+// 写入所有格式, 不支持时只写入纯文本
+pub fn set_formats(formats: Vec<(String, Vec<u8>)>) {
+    let text = formats
+        .iter()
+        .find(|(mime, _)| mime == MIME_TEXT)
+        .map(|(_, data)| String::from_utf8_lossy(data).to_string());
+    // 只有纯文本时由 arboard 写入
+    if let (1, Some(text)) = (formats.len(), &text) {
+        set(text.clone());
+        return;
+    }
+    match write_formats(formats) {
+        Ok(_) => (),
+        Err(error) => {
+            debug!("Unable to write rich text: {}", error);
+            if let Some(text) = text {
+                set(text);
+            }
+        }
+    }
+}
+
+// 解码 png, jpeg 等格式的图片后写入剪贴板
+pub fn set_image(data: Vec<u8>) {
+    let image = match image::load_from_memory(&data) {
+        Ok(image) => image.to_rgba8(),
+        Err(error) => {
+            error!("{}", error);
+            return;
+        }
+    };
+    let mut clipboard = match open() {
+        Some(clipboard) => clipboard,
+        None => return,
+    };
+    let image = ImageData {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        bytes: Cow::from(image.into_raw()),
+    };
+    if let Err(error) = clipboard.set_image(image) {
+        error!("{}", error);
+    }
+}
+
+fn kind(entry: &ClipboardEntry) -> &'static str {
+    if entry.name.is_some() {
+        "file"
+    } else if entry.is_image() {
+        "image"
+    } else {
+        "text"
+    }
+}
+
+fn pipe(command: &str, entry: &ClipboardEntry) -> std::io::Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .env("UNICLIP_TYPE", kind(entry))
+        .env("UNICLIP_MIME", entry.mime())
+        .env("UNICLIP_NAME", entry.name.as_deref().unwrap_or_default())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    // 命令不读取输入就退出时写入会失败, 仍然要等待子进程结束
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(entry.bytes()),
+        None => Ok(()),
+    };
+    let status = child.wait()?;
+    if !status.success() {
+        warn!("\"{}\" exited with {}", command, status);
+    }
+    written
+}
+
+// 写入收到的条目后记录读回的内容
+pub fn mark(hash: &str, origin: &str) {
+    let local = packer::hash(&get(origin));
+    *WRITTEN.lock().unwrap() = Some((hash.to_string(), Some(local)));
+}
+
+// 剪贴板是否仍是指定的条目, 不是写入的条目时直接比较内容, 例如本地复制后发送的内容
+pub fn holds(hash: &str, origin: &str) -> bool {
+    if let Some(offered) = offered() {
+        return offered == hash;
+    }
+    let current = packer::hash(&get(origin));
+    match WRITTEN.lock().unwrap().as_ref() {
+        Some((written, Some(local))) if written == hash => current == *local,
+        _ => current == hash,
+    }
+}
+
+// 清空剪贴板, 无头模式下清空缓冲区
+pub fn clear() {
+    if is_headless() {
+        *OFFERED.lock().unwrap() = None;
+        *BUFFER.lock().unwrap() = None;
+        return;
+    }
+    set(String::new());
+}
+
+// 无头模式下保存收到的内容, 并写入文件或交给命令处理
+pub fn store(entry: ClipboardEntry) {
+    let sink = SINK.read().unwrap().clone();
+    if let Some(path) = &sink.output {
+        if let Err(error) = std::fs::write(path, entry.bytes()) {
+            error!("{}: {}", path.display(), error);
+        }
+    }
+    if let Some(command) = &sink.exec {
+        if let Err(error) = pipe(command, &entry) {
+            error!("{}: {}", command, error);
         }
     }
+    *OFFERED.lock().unwrap() = None;
+    *BUFFER.lock().unwrap() = Some(entry);
 }
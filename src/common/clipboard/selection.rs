@@ -0,0 +1,397 @@
+use super::super::super::datatype::{MIME_HTML, MIME_RTF, MIME_TEXT};
+use super::super::xorg;
+use super::Fetch;
+use std::ffi::CString;
+use std::mem;
+use std::os::raw::{c_int, c_long, c_uchar, c_ulong};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+use x11::xlib;
+
+// 通过 X11 选区读写 HTML, RTF 等格式, arboard 只支持纯文本和图片
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+// 读取属性的最大字节数
+const READ_LIMIT: usize = 32 * 1024 * 1024;
+
+type Format = (String, Vec<u8>);
+
+// 当前拥有剪贴板的连接, 0 表示剪贴板已被其他程序取得
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(1);
+
+// 每种 MIME 类型对应的选区目标, 第一个为首选
+fn targets(mime: &str) -> Vec<&str> {
+    match mime {
+        MIME_TEXT => vec![
+            "UTF8_STRING",
+            "text/plain;charset=utf-8",
+            "text/plain",
+            "STRING",
+            "TEXT",
+        ],
+        MIME_HTML => vec!["text/html"],
+        MIME_RTF => vec!["text/rtf", "application/rtf"],
+        _ => vec![mime],
+    }
+}
+
+// 部分浏览器以带 BOM 的 UTF-16 提供 HTML
+fn normalize(data: Vec<u8>) -> Vec<u8> {
+    let mut data = match data.strip_prefix(&[0xff, 0xfe]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units).into_bytes()
+        }
+        None => data,
+    };
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    data
+}
+
+struct Connection {
+    display: *mut xlib::Display,
+    window: xlib::Window,
+    clipboard: xlib::Atom,
+    property: xlib::Atom,
+}
+
+impl Connection {
+    unsafe fn open() -> Result<Connection, String> {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err("Could not open X display".to_string());
+        }
+        // 请求方窗口已经关闭等错误由 xorg 模块记录, 这里忽略
+        xorg::take_error(display);
+        let root = xlib::XDefaultRootWindow(display);
+        let window = xlib::XCreateSimpleWindow(display, root, 0, 0, 1, 1, 0, 0, 0);
+        let mut conn = Connection {
+            display,
+            window,
+            clipboard: 0,
+            property: 0,
+        };
+        conn.clipboard = conn.atom("CLIPBOARD");
+        conn.property = conn.atom("UNICLIP_SELECTION");
+        Ok(conn)
+    }
+
+    unsafe fn atom(&self, name: &str) -> xlib::Atom {
+        let name = CString::new(name).unwrap();
+        xlib::XInternAtom(self.display, name.as_ptr(), xlib::False)
+    }
+
+    // 单次请求能写入的最大字节数
+    unsafe fn max_size(&self) -> usize {
+        let size = match xlib::XExtendedMaxRequestSize(self.display) {
+            0 => xlib::XMaxRequestSize(self.display),
+            size => size,
+        };
+        (size as usize * 4).saturating_sub(1024)
+    }
+
+    // 请求剪贴板所有者把内容转换为指定格式, 返回属性的格式和内容
+    unsafe fn convert(&self, target: xlib::Atom) -> Option<(c_int, Vec<u8>)> {
+        xlib::XConvertSelection(
+            self.display,
+            self.clipboard,
+            target,
+            self.property,
+            self.window,
+            xlib::CurrentTime,
+        );
+        xlib::XFlush(self.display);
+
+        let start = Instant::now();
+        let mut event: xlib::XEvent = mem::zeroed();
+        while xlib::XCheckTypedWindowEvent(
+            self.display,
+            self.window,
+            xlib::SelectionNotify,
+            &mut event,
+        ) == 0
+        {
+            if start.elapsed() > TIMEOUT {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        if event.selection.property == 0 {
+            return None;
+        }
+
+        let mut kind = 0;
+        let mut format = 0;
+        let mut items: c_ulong = 0;
+        let mut after: c_ulong = 0;
+        let mut data: *mut c_uchar = ptr::null_mut();
+        let res = xlib::XGetWindowProperty(
+            self.display,
+            self.window,
+            self.property,
+            0,
+            (READ_LIMIT / 4) as c_long,
+            xlib::True,
+            xlib::AnyPropertyType as xlib::Atom,
+            &mut kind,
+            &mut format,
+            &mut items,
+            &mut after,
+            &mut data,
+        );
+        if res != xlib::Success as c_int || data.is_null() {
+            return None;
+        }
+        // 分段传输的大内容不支持
+        let size = match format {
+            _ if kind == self.atom("INCR") => None,
+            8 => Some(items as usize),
+            16 => Some(items as usize * 2),
+            32 => Some(items as usize * mem::size_of::<c_long>()),
+            _ => None,
+        };
+        let res = size.map(|size| (format, std::slice::from_raw_parts(data, size).to_vec()));
+        xlib::XFree(data as *mut _);
+        res
+    }
+
+    unsafe fn available(&self) -> Vec<xlib::Atom> {
+        match self.convert(self.atom("TARGETS")) {
+            Some((32, data)) => data
+                .chunks_exact(mem::size_of::<xlib::Atom>())
+                .map(|c| xlib::Atom::from_ne_bytes(c.try_into().unwrap()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    unsafe fn respond(
+        &self,
+        req: &xlib::XSelectionRequestEvent,
+        offered: &[(xlib::Atom, String)],
+        formats: &[Format],
+    ) {
+        let targets = self.atom("TARGETS");
+        // 旧的客户端可能不指定属性
+        let property = match req.property {
+            0 => req.target,
+            property => property,
+        };
+        let mut reply = property;
+        if req.target == targets {
+            let mut atoms = vec![targets];
+            atoms.extend(offered.iter().map(|(atom, _)| *atom));
+            xlib::XChangeProperty(
+                self.display,
+                req.requestor,
+                property,
+                xlib::XA_ATOM,
+                32,
+                xlib::PropModeReplace,
+                atoms.as_ptr() as *const c_uchar,
+                atoms.len() as c_int,
+            );
+        } else {
+            let format = offered
+                .iter()
+                .find(|(atom, _)| *atom == req.target)
+                .and_then(|(_, mime)| formats.iter().find(|(m, _)| m == mime));
+            match format {
+                Some((_, data)) if data.len() <= self.max_size() => {
+                    xlib::XChangeProperty(
+                        self.display,
+                        req.requestor,
+                        property,
+                        req.target,
+                        8,
+                        xlib::PropModeReplace,
+                        data.as_ptr(),
+                        data.len() as c_int,
+                    );
+                }
+                Some((mime, _)) => {
+                    debug!("{} is too large for the X selection", mime);
+                    reply = 0;
+                }
+                None => reply = 0,
+            }
+        }
+
+        let mut event = xlib::XEvent {
+            selection: xlib::XSelectionEvent {
+                type_: xlib::SelectionNotify,
+                serial: 0,
+                send_event: xlib::True,
+                display: self.display,
+                requestor: req.requestor,
+                selection: req.selection,
+                target: req.target,
+                property: reply,
+                time: req.time,
+            },
+        };
+        xlib::XSendEvent(
+            self.display,
+            req.requestor,
+            xlib::False,
+            xlib::NoEventMask,
+            &mut event,
+        );
+        xlib::XFlush(self.display);
+    }
+
+    // 一直响应其他程序的请求, 直到剪贴板被其他程序取得.
+    // 有 fetch 时内容在第一次被请求时在其他线程获取, 获取完成后回应该请求,
+    // 获取期间的其他请求直接拒绝
+    unsafe fn serve(
+        &self,
+        id: usize,
+        mimes: Vec<String>,
+        mut formats: Vec<Format>,
+        mut fetch: Option<Fetch>,
+        ready: mpsc::Sender<Result<(), String>>,
+    ) {
+        let mut offered = Vec::new();
+        for mime in mimes.iter() {
+            for target in targets(mime) {
+                offered.push((self.atom(target), mime.clone()));
+            }
+        }
+        let targets = self.atom("TARGETS");
+        xlib::XSetSelectionOwner(self.display, self.clipboard, self.window, xlib::CurrentTime);
+        if xlib::XGetSelectionOwner(self.display, self.clipboard) != self.window {
+            let _ = ready.send(Err("Unable to own the clipboard".to_string()));
+            return;
+        }
+        OWNER.store(id, Ordering::SeqCst);
+        let _ = ready.send(Ok(()));
+
+        // 等待内容的请求和获取结果
+        let mut waiting: Option<(xlib::XSelectionRequestEvent, mpsc::Receiver<_>)> = None;
+        let mut cleared = false;
+        // 剪贴板被取得后仍然回应等待中的请求
+        while !cleared || waiting.is_some() {
+            let done = match &waiting {
+                Some((req, fetched)) => match fetched.try_recv() {
+                    Ok(res) => Some((*req, res)),
+                    Err(mpsc::TryRecvError::Empty) => None,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        Some((*req, Err("Fetch failed".into())))
+                    }
+                },
+                None => None,
+            };
+            if let Some((req, res)) = done {
+                match res {
+                    Ok(fetched) => formats = fetched,
+                    Err(error) => error!("{}", error),
+                }
+                self.respond(&req, &offered, &formats);
+                waiting = None;
+            }
+            // 没有等待的请求时阻塞等待事件
+            if waiting.is_some() && xlib::XPending(self.display) == 0 {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            let mut event: xlib::XEvent = mem::zeroed();
+            xlib::XNextEvent(self.display, &mut event);
+            match event.get_type() {
+                xlib::SelectionRequest => {
+                    let req = event.selection_request;
+                    // 查询格式列表时不需要内容, 获取失败时不再重试
+                    if let Some(fetch) = fetch.take_if(|_| req.target != targets) {
+                        let (sender, fetched) = mpsc::channel();
+                        thread::spawn(move || sender.send(fetch()));
+                        waiting = Some((req, fetched));
+                    } else if waiting.is_some() && req.target != targets {
+                        self.respond(&req, &offered, &[]);
+                    } else {
+                        self.respond(&req, &offered, &formats);
+                    }
+                }
+                xlib::SelectionClear => {
+                    // 新的连接可能已经取得剪贴板
+                    let _ = OWNER.compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst);
+                    cleared = true;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XDestroyWindow(self.display, self.window);
+            xlib::XCloseDisplay(self.display);
+            xorg::take_error(self.display);
+        }
+    }
+}
+
+// 读取剪贴板中的指定格式, 剪贴板中没有的格式不返回
+pub fn read(mimes: &[&str]) -> Result<Vec<Format>, String> {
+    unsafe {
+        let conn = Connection::open()?;
+        let available = conn.available();
+        let mut res = Vec::new();
+        for mime in mimes.iter() {
+            for target in targets(mime) {
+                let atom = conn.atom(target);
+                if !available.contains(&atom) {
+                    continue;
+                }
+                if let Some((8, data)) = conn.convert(atom) {
+                    res.push((mime.to_string(), normalize(data)));
+                    break;
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+// 同时提供所有格式, 由粘贴的程序选择
+pub fn write(formats: Vec<Format>) -> Result<(), String> {
+    let mimes = formats.iter().map(|(mime, _)| mime.clone()).collect();
+    own(mimes, formats, None)
+}
+
+// 提供指定的格式, 有程序粘贴时才获取内容
+pub fn offer(mimes: Vec<String>, fetch: Fetch) -> Result<(), String> {
+    own(mimes, Vec::new(), Some(fetch))
+}
+
+// 本程序是否仍拥有剪贴板
+pub fn owned() -> bool {
+    OWNER.load(Ordering::SeqCst) != 0
+}
+
+fn own(mimes: Vec<String>, formats: Vec<Format>, fetch: Option<Fetch>) -> Result<(), String> {
+    let (ready, res) = mpsc::channel();
+    let id = NEXT_OWNER.fetch_add(1, Ordering::SeqCst);
+    thread::spawn(move || unsafe {
+        match Connection::open() {
+            Ok(conn) => conn.serve(id, mimes, formats, fetch, ready),
+            Err(error) => {
+                let _ = ready.send(Err(error));
+            }
+        }
+    });
+    match res.recv_timeout(TIMEOUT) {
+        Ok(res) => res,
+        Err(_) => Err("Timed out waiting for the X selection".to_string()),
+    }
+}
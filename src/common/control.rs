@@ -0,0 +1,393 @@
+use super::super::datatype::{ClipboardEntry, EntryInfo, RemoteClipboard};
+use super::{address, progress, uniclip};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::time;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::error;
+
+const PREVIEW_LIMIT: usize = 40;
+
+// 控制协议: 客户端发送一行命令, 服务端返回 "ok" 或 "error <原因>", 之后是输出内容.
+// send 命令的格式为 "send <type> <length> <secret> <name>", 之后是 length 字节的内容,
+// secret 为 1 时内容由调用方标记为敏感
+pub async fn start(path: &Path, port: u16) -> io::Result<()> {
+    // 已有节点在运行时不覆盖它的控制接口
+    if StdUnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is used by another instance", path.display()),
+        ));
+    }
+    let _ = fs::remove_file(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let listener = bind(path)?;
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, port));
+                }
+                Err(error) => {
+                    error!("Control: {}", error);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// 先在只有自己可以访问的目录中创建并设置权限, 再移动到目标路径,
+// 避免其他用户在 bind 和 chmod 之间连接
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("control.sock");
+    let res = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    res
+}
+
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+async fn serve(stream: UnixStream, port: u16) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = AsyncBufReader::new(reader);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.is_err() {
+        return;
+    }
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let res = match words.first() {
+        Some(&"send") => send(line.trim_end(), &mut reader).await,
+        Some(&"recv") => recv().await,
+        Some(&"get") => uniclip::paste().await.map(into_bytes),
+        _ => handle(&words, port).await.map(String::into_bytes),
+    };
+    // 输出可能很大, 不与状态行拼接
+    let _ = match res {
+        Ok(output) => match writer.write_all(b"ok\n").await {
+            Ok(_) => writer.write_all(&output).await,
+            Err(error) => Err(error),
+        },
+        Err(error) => {
+            writer
+                .write_all(format!("error {}\n", error).as_bytes())
+                .await
+        }
+    };
+}
+
+fn into_bytes(mut entry: ClipboardEntry) -> Vec<u8> {
+    match entry.formats.is_empty() {
+        true => Vec::new(),
+        false => entry.formats.swap_remove(0).1,
+    }
+}
+
+async fn send<R: AsyncReadExt + Unpin>(line: &str, reader: &mut R) -> Result<Vec<u8>, String> {
+    let words: Vec<&str> = line.splitn(5, ' ').collect();
+    let (kind, length, secret, name) = match words[..] {
+        [_, kind, length, secret @ ("0" | "1"), name] => (kind, length, secret == "1", name),
+        _ => return Err(format!("Invalid command \"{}\"", line)),
+    };
+    let length = match length.parse::<u64>() {
+        Ok(length) => length,
+        Err(_) => return Err(format!("Invalid length \"{}\"", length)),
+    };
+    // 按实际收到的内容分配, 不按声明的长度预先分配
+    let mut buffer = Vec::new();
+    match reader.take(length).read_to_end(&mut buffer).await {
+        Ok(size) if size as u64 == length => (),
+        Ok(_) => return Err("Unexpected end of data".to_string()),
+        Err(error) => return Err(format!("{}", error)),
+    }
+    let origin = uniclip::node_id();
+    let entry = match kind {
+        "text" => match String::from_utf8(buffer) {
+            Ok(text) => ClipboardEntry::from_text(&origin, text),
+            Err(_) => return Err("Text is not valid UTF-8".to_string()),
+        },
+        "image" => match image_mime(&buffer) {
+            Some(mime) => ClipboardEntry::new(&origin, vec![(mime.to_string(), buffer)]),
+            None => return Err("Unsupported image format".to_string()),
+        },
+        "file" => ClipboardEntry::from_file(&origin, name, buffer),
+        _ => return Err(format!("Invalid type \"{}\"", kind)),
+    };
+    let entry = ClipboardEntry {
+        sensitive: secret,
+        ..entry
+    };
+    let count = uniclip::send(entry).await?;
+    Ok(format!("Sent to {} peers\n", count).into_bytes())
+}
+
+async fn recv() -> Result<Vec<u8>, String> {
+    match uniclip::recv().await {
+        Some(data) => Ok(data.bytes().to_vec()),
+        None => Err("Node is shutting down".to_string()),
+    }
+}
+
+async fn handle(words: &[&str], port: u16) -> Result<String, String> {
+    match words {
+        ["status"] => Ok(status()),
+        ["peers"] => Ok(peers()),
+        ["push"] => Ok(format!("Sent to {} peers\n", uniclip::push().await?)),
+        ["pull"] => Ok(format!("Requested from {} peers\n", uniclip::pull().await)),
+        ["history"] => Ok(history()),
+        ["connect", remote] => {
+            let (host, remote_port) = address::parse_remote(remote)?;
+            let remote = RemoteClipboard {
+                host,
+                port: remote_port.unwrap_or(port),
+            };
+            let peer_id = uniclip::dial(remote.clone()).await?;
+            Ok(format!("Connected to {} ({})\n", remote, peer_id))
+        }
+        ["disconnect", target] => {
+            let closed = uniclip::disconnect(target);
+            if closed.is_empty() {
+                return Err(format!("No peer matches {}", target));
+            }
+            Ok(closed
+                .iter()
+                .map(|node_id| format!("Disconnected {}\n", node_id))
+                .collect())
+        }
+        ["pending"] => Ok(pending()),
+        ["offers"] => Ok(offers()),
+        ["fetch"] => Ok(format!("Fetched {}\n", uniclip::fetch("").await?)),
+        ["fetch", id] => Ok(format!("Fetched {}\n", uniclip::fetch(id).await?)),
+        ["transfers"] => Ok(transfers()),
+        ["cancel", id] => Ok(format!("Cancelled {}\n", uniclip::cancel(id).await?)),
+        ["accept", id] => Ok(format!("Accepted {}\n", uniclip::answer(id, true)?)),
+        ["reject", id] => Ok(format!("Rejected {}\n", uniclip::answer(id, false)?)),
+        ["quit"] => {
+            uniclip::request_shutdown();
+            Ok("Shutting down\n".to_string())
+        }
+        _ => Err(format!("Invalid command \"{}\"", words.join(" "))),
+    }
+}
+
+fn status() -> String {
+    let status = uniclip::status();
+    let listen: Vec<String> = status.listen.iter().map(|a| a.to_string()).collect();
+    let mut res = String::new();
+    res += &format!("node     {}\n", status.node_id);
+    res += &format!("listen   {}\n", listen.join(", "));
+    res += &format!("peers    {}\n", status.peers);
+    res += &format!("members  {}\n", status.members);
+    res += &format!("relay    {}\n", if status.relay { "on" } else { "off" });
+    res += &format!(
+        "tls      {}\n",
+        status.fingerprint.unwrap_or_else(|| "off".to_string())
+    );
+    // 最近一次因为像密钥或密码而没有发送的内容
+    if let Some((time, reason)) = status.last_blocked {
+        let ago = time::SystemTime::now()
+            .duration_since(time)
+            .unwrap_or_default()
+            .as_secs();
+        res += &format!(
+            "secrets  {} not sent, last {}s ago ({})\n",
+            status.blocked, ago, reason
+        );
+    }
+    res
+}
+
+fn peers() -> String {
+    let mut res = String::new();
+    for peer in uniclip::peers() {
+        let mode = match (peer.can_send, peer.can_recv) {
+            (true, true) => "both",
+            (true, false) => "send",
+            (false, true) => "recv",
+            (false, false) => "off",
+        };
+        let direction = if peer.outgoing { "out" } else { "in" };
+        res += &format!(
+            "{}  {}  {}  {}\n",
+            peer.node_id, peer.remote, direction, mode
+        );
+    }
+    res
+}
+
+fn transfers() -> String {
+    let mut res = String::new();
+    for item in progress::list() {
+        let direction = if item.sending { "send" } else { "recv" };
+        let eta = match item.eta() {
+            Some(eta) if item.done > 0 => format!("{}s", eta.as_secs()),
+            _ => "-".to_string(),
+        };
+        res += &format!(
+            "{}  {}  {}  {}/{} bytes  {:.0}%  {:.1} KB/s  eta {}\n",
+            item.id,
+            direction,
+            item.node_id,
+            item.done,
+            item.size,
+            item.percent(),
+            item.rate() / 1024.0,
+            eta
+        );
+    }
+    res
+}
+
+fn describe(info: &EntryInfo) -> String {
+    match &info.name {
+        Some(name) => format!("[file {}]", name),
+        None => {
+            let mimes: Vec<&str> = info.formats.iter().map(|(m, _)| &m[..]).collect();
+            format!("[{}]", mimes.join(", "))
+        }
+    }
+}
+
+fn pending() -> String {
+    let mut res = String::new();
+    for item in uniclip::pending() {
+        res += &format!(
+            "{}  {}  {} bytes  {}\n",
+            item.id,
+            item.node_id,
+            item.info.size(),
+            describe(&item.info)
+        );
+    }
+    res
+}
+
+fn offers() -> String {
+    let mut res = String::new();
+    for item in uniclip::offers() {
+        let content = match item.preview.is_empty() {
+            true => describe(&item.info),
+            false => shorten(&item.preview),
+        };
+        res += &format!(
+            "{}  {}  {} bytes  {}\n",
+            item.route.id,
+            item.route.origin,
+            item.info.size(),
+            content
+        );
+    }
+    res
+}
+
+// 只显示第一部分, 换行显示为 \n
+fn shorten(text: &str) -> String {
+    let line = text.replace('\n', "\\n");
+    match line.char_indices().nth(PREVIEW_LIMIT) {
+        Some((index, _)) => format!("{}...", &line[..index]),
+        None => line,
+    }
+}
+
+fn preview(entry: &ClipboardEntry) -> String {
+    if let Some(name) = &entry.name {
+        return format!("[file {}]", name);
+    }
+    if entry.is_image() {
+        return format!("[{}]", entry.mime());
+    }
+    shorten(&String::from_utf8_lossy(entry.bytes()))
+}
+
+fn history() -> String {
+    let now = time::SystemTime::now();
+    let local_id = uniclip::node_id();
+    let mut res = String::new();
+    for item in uniclip::history().iter().rev() {
+        let ago = now.duration_since(item.time).unwrap_or_default().as_secs();
+        // 经中继收到的更新同时显示转发的节点
+        let source = if item.source == local_id {
+            "local".to_string()
+        } else if item.entry.origin != item.source {
+            format!("{} via {}", item.entry.origin, item.source)
+        } else {
+            item.source.clone()
+        };
+        res += &format!(
+            "{:>6}s ago  {}  {} bytes  {}\n",
+            ago,
+            source,
+            item.entry.size(),
+            preview(&item.entry)
+        );
+    }
+    res
+}
+
+fn image_mime(data: &[u8]) -> Option<&'static str> {
+    const IMAGE_MAGIC: [(&[u8], &str); 4] = [
+        (b"\x89PNG", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF8", "image/gif"),
+        (b"BM", "image/bmp"),
+    ];
+    IMAGE_MAGIC
+        .iter()
+        .find(|(magic, _)| data.starts_with(magic))
+        .map(|(_, mime)| *mime)
+}
+
+// 根据内容判断类型: 图片, 文本或文件
+pub fn detect(data: &[u8]) -> &'static str {
+    if image_mime(data).is_some() {
+        "image"
+    } else if !data.contains(&0) && std::str::from_utf8(data).is_ok() {
+        "text"
+    } else {
+        "file"
+    }
+}
+
+// 客户端: 发送命令和内容并返回输出, 服务端返回错误时为 Err
+pub fn request(path: &Path, command: &[String], body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut stream = StdUnixStream::connect(path)
+        .map_err(|error| format!("Unable to connect to {}: {}", path.display(), error))?;
+    let line = format!("{}\n", command.join(" "));
+    stream
+        .write_all(line.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .map_err(|error| format!("{}", error))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader
+        .read_line(&mut status)
+        .map_err(|error| format!("{}", error))?;
+    let mut output = Vec::new();
+    reader
+        .read_to_end(&mut output)
+        .map_err(|error| format!("{}", error))?;
+
+    match status.trim_end().split_once(' ') {
+        _ if status.trim_end() == "ok" => Ok(output),
+        Some(("error", error)) => Err(error.to_string()),
+        _ => Err("Invalid response".to_string()),
+    }
+}
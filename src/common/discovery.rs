@@ -0,0 +1,252 @@
+use super::super::datatype::{RemoteClipboard, UNICLIP_PROTO_VERSION};
+use hmac::{Hmac, Mac};
+use serde_encrypt::{shared_key::SharedKey, AsSharedKey};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time;
+use tokio::net::UdpSocket;
+use tracing::error;
+
+pub const DISCOVERY_ADDR: &str = "239.255.85.67:10501";
+pub const DISCOVERY_INTERVAL: u64 = 5;
+
+const ANNOUNCE_TAG: &str = "UNICLIP";
+
+// 局域网广播的节点信息, 格式: UNICLIP <version> <node id> <port> <proof>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub version: u8,
+    pub node_id: String,
+    pub port: u16,
+    pub proof: String,
+}
+
+fn proof(key: &SharedKey, version: u8, node_id: &str, port: u16) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_slice()).unwrap();
+    mac.update(format!("{}|{}|{}|{}", ANNOUNCE_TAG, version, node_id, port).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+impl Announce {
+    pub fn new(key: &SharedKey, node_id: &str, port: u16) -> Announce {
+        Announce {
+            version: UNICLIP_PROTO_VERSION,
+            node_id: node_id.to_string(),
+            port,
+            proof: proof(key, UNICLIP_PROTO_VERSION, node_id, port),
+        }
+    }
+
+    // 只有持有相同密钥的节点才能生成正确的 proof
+    pub fn verify(&self, key: &SharedKey) -> bool {
+        self.version == UNICLIP_PROTO_VERSION
+            && proof(key, self.version, &self.node_id, self.port) == self.proof
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            ANNOUNCE_TAG, self.version, self.node_id, self.port, self.proof
+        )
+    }
+
+    pub fn decode(data: &str) -> Option<Announce> {
+        let words: Vec<&str> = data.split_whitespace().collect();
+        if words.len() != 5 || words[0] != ANNOUNCE_TAG {
+            return None;
+        }
+        Some(Announce {
+            version: words[1].parse().ok()?,
+            node_id: words[2].to_string(),
+            port: words[3].parse().ok()?,
+            proof: words[4].to_string(),
+        })
+    }
+}
+
+pub struct Discovery {
+    key: SharedKey,
+    node_id: String,
+    port: u16,
+    group: SocketAddrV4,
+    interface: Ipv4Addr,
+    on_found: Arc<dyn Fn(String, RemoteClipboard) + Send + Sync>,
+}
+
+impl Discovery {
+    pub fn new(
+        key: SharedKey,
+        node_id: String,
+        port: u16,
+        group: SocketAddrV4,
+        interface: Ipv4Addr,
+        on_found: Arc<dyn Fn(String, RemoteClipboard) + Send + Sync>,
+    ) -> Discovery {
+        Discovery {
+            key,
+            node_id,
+            port,
+            group,
+            interface,
+            on_found,
+        }
+    }
+
+    fn bind(&self) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // 允许同一台机器上的多个实例监听同一个端口
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.group.port());
+        socket.bind(&SocketAddr::V4(bind_addr).into())?;
+
+        let group = *self.group.ip();
+        if group.is_multicast() {
+            socket.join_multicast_v4(&group, &self.interface)?;
+            socket.set_multicast_if_v4(&self.interface)?;
+            socket.set_multicast_loop_v4(true)?;
+        } else {
+            socket.set_broadcast(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    pub async fn start(self) -> io::Result<()> {
+        let socket = Arc::new(self.bind()?);
+        let sender = socket.clone();
+
+        let announce = Announce::new(&self.key, &self.node_id, self.port).encode();
+        let group = self.group;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(time::Duration::from_secs(DISCOVERY_INTERVAL));
+            loop {
+                interval.tick().await;
+                if let Err(error) = sender.send_to(announce.as_bytes(), group).await {
+                    error!("Discovery: {}", error);
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            loop {
+                let (size, addr) = match socket.recv_from(&mut buffer).await {
+                    Ok(res) => res,
+                    Err(error) => {
+                        error!("Discovery: {}", error);
+                        continue;
+                    }
+                };
+                let announce = match std::str::from_utf8(&buffer[..size])
+                    .ok()
+                    .and_then(Announce::decode)
+                {
+                    Some(announce) => announce,
+                    None => continue,
+                };
+                if announce.node_id == self.node_id || !announce.verify(&self.key) {
+                    continue;
+                }
+                let remote = RemoteClipboard {
+                    host: addr.ip().to_string(),
+                    port: announce.port,
+                };
+                (self.on_found)(announce.node_id, remote);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn key(password: &str) -> SharedKey {
+        super::super::packer::pwd2key(password.to_string())
+    }
+
+    #[test]
+    fn announce_round_trip() {
+        let announce = Announce::new(&key("secret"), "node-a", 10500);
+        let decoded = Announce::decode(&announce.encode()).unwrap();
+        assert_eq!(decoded, announce);
+        assert!(decoded.verify(&key("secret")));
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        assert!(Announce::decode("").is_none());
+        assert!(Announce::decode("OTHER 1 node-a 10500 00").is_none());
+        assert!(Announce::decode("UNICLIP 1 node-a port 00").is_none());
+        assert!(Announce::decode("UNICLIP 1 node-a 10500").is_none());
+    }
+
+    #[test]
+    fn verify_rejects_wrong_key() {
+        let announce = Announce::new(&key("secret"), "node-a", 10500);
+        assert!(!announce.verify(&key("other")));
+
+        // 修改端口或节点 ID 后 proof 不再匹配
+        let mut forged = announce.clone();
+        forged.port = 10501;
+        assert!(!forged.verify(&key("secret")));
+        let mut forged = announce;
+        forged.node_id = "node-b".to_string();
+        assert!(!forged.verify(&key("secret")));
+    }
+
+    fn start(
+        node_id: &str,
+        port: u16,
+        group: SocketAddrV4,
+        found: mpsc::UnboundedSender<(String, String, RemoteClipboard)>,
+    ) -> Discovery {
+        let local = node_id.to_string();
+        Discovery::new(
+            key("secret"),
+            node_id.to_string(),
+            port,
+            group,
+            Ipv4Addr::LOCALHOST,
+            Arc::new(move |node_id, remote| {
+                let _ = found.send((local.clone(), node_id, remote));
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn instances_find_each_other_on_loopback() {
+        // 两个实例通过 reuse_port 监听同一个端口
+        let port = 20000 + (std::process::id() % 10000) as u16;
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 85, 67), port);
+        let (found, mut results) = mpsc::unbounded_channel();
+        start("node-a", 10500, group, found.clone())
+            .start()
+            .await
+            .unwrap();
+        start("node-b", 10501, group, found).start().await.unwrap();
+
+        let mut seen = Vec::new();
+        let deadline = tokio::time::sleep(time::Duration::from_secs(3));
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                res = results.recv() => {
+                    let (local, node_id, remote) = res.unwrap();
+                    assert_ne!(local, node_id, "{} found itself", local);
+                    seen.push((local, node_id, remote.port));
+                }
+                _ = &mut deadline => break,
+            }
+        }
+        assert!(seen.contains(&("node-a".to_string(), "node-b".to_string(), 10501)));
+        assert!(seen.contains(&("node-b".to_string(), "node-a".to_string(), 10500)));
+    }
+}
@@ -1,69 +1,400 @@
+use super::super::datatype::{HotkeyBackend, HotkeyConfig};
 use device_query::{DeviceQuery, DeviceState};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::warn;
 
 pub use device_query::Keycode;
 
+#[cfg(target_os = "linux")]
+mod grab;
+
+impl FromStr for HotkeyBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poll" => Ok(HotkeyBackend::Poll),
+            "grab" => Ok(HotkeyBackend::Grab),
+            _ => Err(format!(
+                "Invalid hotkey backend \"{}\", expected poll or grab",
+                s
+            )),
+        }
+    }
+}
+
+// 解析快捷键, 同时按下的键用 '+' 连接, 依次按下松开的组合用 ',' 分隔,
+// 例如 "LControl+LShift+C" 或 "LControl,LControl" (双击 Ctrl)
+pub fn parse_keys(s: &str) -> Result<Vec<Vec<Keycode>>, String> {
+    let mut steps = Vec::new();
+    for step in s.split(',') {
+        let mut keys = Vec::new();
+        for key in step.split('+') {
+            let key = key.trim();
+            match Keycode::from_str(key) {
+                Ok(key) if !keys.contains(&key) => keys.push(key),
+                Ok(_) => (),
+                Err(_) => return Err(format!("Invalid key \"{}\"", key)),
+            }
+        }
+        if keys.is_empty() {
+            return Err(format!("Invalid hotkey \"{}\"", s));
+        }
+        steps.push(keys);
+    }
+    Ok(steps)
+}
+
+// 按键来源, 可以替换为模拟的按键序列
+pub trait KeySource {
+    fn keys(&mut self) -> Vec<Keycode>;
+}
+
+impl KeySource for DeviceState {
+    fn keys(&mut self) -> Vec<Keycode> {
+        self.get_keys()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Hotkey {
-    keys: Vec<Keycode>,
+    steps: Vec<Vec<Keycode>>,
     callback: fn(),
+    debounce: Duration,
+    window: Duration,
 }
 
 impl Hotkey {
-    pub fn new(keys: Vec<Keycode>, callback: fn()) -> Self {
-        Self { keys, callback }
+    // 只有一步时为同时按下的组合键, 否则依次按下并松开每一步, 每一步之间不超过 window
+    pub fn sequence(steps: Vec<Vec<Keycode>>, callback: fn()) -> Self {
+        Self {
+            steps,
+            callback,
+            debounce: Duration::from_millis(300),
+            window: Duration::from_millis(400),
+        }
+    }
+
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct HotkeyState {
+    active: bool, // 当前一步的组合键已按下, 尚未完全松开
+    step: usize,
+    pressed_at: Option<Instant>,
+    released_at: Option<Instant>,
+    fired_at: Option<Instant>,
+}
+
+fn elapsed(since: Option<Instant>, now: Instant) -> Option<Duration> {
+    since.map(|since| now.saturating_duration_since(since))
+}
+
+fn same_keys(a: &[Keycode], b: &[Keycode]) -> bool {
+    a.len() == b.len() && a.iter().all(|key| b.contains(key))
+}
+
+impl HotkeyState {
+    fn debounced(&mut self, hotkey: &Hotkey, now: Instant) -> bool {
+        if elapsed(self.fired_at, now).is_some_and(|d| d < hotkey.debounce) {
+            return false;
+        }
+        self.fired_at = Some(now);
+        true
+    }
+
+    fn reset(&mut self) {
+        self.active = false;
+        self.step = 0;
+    }
+
+    // 按下组合键的瞬间触发, 按住时其他键变化不会再次触发
+    fn update_combination(&mut self, hotkey: &Hotkey, keys: &[Keycode], now: Instant) -> bool {
+        let active = hotkey.steps[0].iter().all(|key| keys.contains(key));
+        let pressed = active && !self.active;
+        self.active = active;
+        pressed && self.debounced(hotkey, now)
+    }
+
+    // 每一步必须只按下该步的键, 按下和松开之间以及两步之间都不超过 window
+    fn update_sequence(
+        &mut self,
+        hotkey: &Hotkey,
+        keys: &[Keycode],
+        others: bool,
+        now: Instant,
+    ) -> bool {
+        if self.active {
+            let step = &hotkey.steps[self.step];
+            if others || !keys.iter().all(|key| step.contains(key)) {
+                self.reset();
+                return false;
+            }
+            if !keys.is_empty() {
+                return false;
+            }
+            self.active = false;
+            if elapsed(self.pressed_at, now).is_some_and(|d| d > hotkey.window) {
+                self.step = 0;
+                return false;
+            }
+            self.step += 1;
+            self.released_at = Some(now);
+            if self.step < hotkey.steps.len() {
+                return false;
+            }
+            self.step = 0;
+            return self.debounced(hotkey, now);
+        }
+
+        if self.step > 0 && elapsed(self.released_at, now).is_some_and(|d| d > hotkey.window) {
+            self.step = 0;
+        }
+        if !others && same_keys(&hotkey.steps[self.step], keys) {
+            self.active = true;
+            self.pressed_at = Some(now);
+        } else if self.step > 0 && !keys.is_empty() {
+            self.step = 0;
+        }
+        false
     }
 }
 
 pub struct HotkeyManager {
     hot_keys: Vec<Hotkey>,
+    states: Vec<HotkeyState>,
+    watched: Vec<Keycode>,
+    backend: HotkeyBackend,
+    interval: Duration,
 }
 
 impl HotkeyManager {
-    pub fn new() -> Self {
-        let hm = Self {
+    pub fn new(backend: HotkeyBackend) -> Self {
+        Self {
             hot_keys: Vec::new(),
-        };
-        hm
+            states: Vec::new(),
+            watched: Vec::new(),
+            backend,
+            interval: Duration::from_millis(30),
+        }
+    }
+
+    pub fn from_config(config: &HotkeyConfig, callback: fn()) -> Self {
+        let mut manager = Self::new(config.backend);
+        manager.interval = Duration::from_millis(config.interval);
+        let hotkey = Hotkey::sequence(config.keys.clone(), callback)
+            .debounce(Duration::from_millis(config.debounce))
+            .window(Duration::from_millis(config.window));
+        manager.register(hotkey);
+        manager
     }
 
-    fn match_keys(keys: &Vec<Keycode>, hot_keys: &Vec<Keycode>) -> bool {
-        let mut matched = true;
-        for key in hot_keys.iter() {
-            if !keys.contains(key) {
-                matched = false;
-                break;
+    // 只保留快捷键用到的按键, 其他按键只记录是否有按下, 返回触发的快捷键序号
+    pub fn update(&mut self, keys: &[Keycode], now: Instant) -> Vec<usize> {
+        let others = keys.iter().any(|key| !self.watched.contains(key));
+        let keys: Vec<Keycode> = keys
+            .iter()
+            .filter(|key| self.watched.contains(key))
+            .cloned()
+            .collect();
+
+        let mut fired = Vec::new();
+        for (i, (hotkey, state)) in self.hot_keys.iter().zip(self.states.iter_mut()).enumerate() {
+            let res = if hotkey.steps.len() == 1 {
+                state.update_combination(hotkey, &keys, now)
+            } else {
+                state.update_sequence(hotkey, &keys, others, now)
+            };
+            if res {
+                fired.push(i);
             }
         }
-        matched
+        fired
     }
 
-    fn check_hooks(keys: Vec<Keycode>, hot_keys: &Vec<Hotkey>) {
-        for hk in hot_keys {
-            if Self::match_keys(&keys, &hk.keys) {
-                (hk.callback)();
-            }
+    // 读取一次按键状态并调用触发的快捷键
+    pub fn poll_once(&mut self, source: &mut dyn KeySource, now: Instant) {
+        let keys = source.keys();
+        for i in self.update(&keys, now) {
+            (self.hot_keys[i].callback)();
+        }
+    }
+
+    fn poll(mut self, mut source: impl KeySource) {
+        loop {
+            std::thread::sleep(self.interval);
+            self.poll_once(&mut source, Instant::now());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn grab(self) {
+        if let Err(error) = grab::listen(&self.hot_keys) {
+            warn!("Unable to register hotkey: {}, fall back to polling", error);
+            self.poll(DeviceState::new());
         }
     }
 
-    pub fn listen(&self) {
-        let hot_keys = self.hot_keys.clone();
+    #[cfg(not(target_os = "linux"))]
+    fn grab(self) {
+        warn!("Hotkey registration is only supported on X11, fall back to polling");
+        self.poll(DeviceState::new());
+    }
+
+    pub fn listen(self) {
         // 开启新线程 用于监听键盘事件
-        std::thread::spawn(move || {
-            let mut keys: Vec<Keycode> = Vec::new();
-            let device_state = DeviceState::new();
-            loop {
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                let keys_down = device_state.get_keys();
-                if keys_down.ne(&keys) {
-                    println!("keys: {:?}", keys_down);
-                    keys = keys_down.clone();
-                    Self::check_hooks(keys_down, &hot_keys);
-                }
-            }
+        std::thread::spawn(move || match self.backend {
+            HotkeyBackend::Poll => self.poll(DeviceState::new()),
+            HotkeyBackend::Grab => self.grab(),
         });
     }
 
     pub fn register(&mut self, hotkey: Hotkey) {
-        self.hot_keys.push(hotkey.clone());
+        for key in hotkey.steps.iter().flatten() {
+            if !self.watched.contains(key) {
+                self.watched.push(*key);
+            }
+        }
+        self.hot_keys.push(hotkey);
+        self.states.push(HotkeyState::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // 按顺序返回预设的按键状态
+    struct FakeKeys(Vec<Vec<Keycode>>);
+
+    impl KeySource for FakeKeys {
+        fn keys(&mut self) -> Vec<Keycode> {
+            if self.0.is_empty() {
+                Vec::new()
+            } else {
+                self.0.remove(0)
+            }
+        }
+    }
+
+    const CHORD: [Keycode; 2] = [Keycode::LControl, Keycode::C];
+
+    // 每一帧为 (距 start 的毫秒数, 按下的键)
+    fn drive(manager: &mut HotkeyManager, start: Instant, frames: &[(u64, &[Keycode])]) {
+        let mut source = FakeKeys(frames.iter().map(|(_, keys)| keys.to_vec()).collect());
+        for (ms, _) in frames.iter() {
+            manager.poll_once(&mut source, start + Duration::from_millis(*ms));
+        }
+    }
+
+    fn chord(callback: fn()) -> HotkeyManager {
+        let mut manager = HotkeyManager::new(HotkeyBackend::Poll);
+        manager.register(Hotkey::sequence(vec![CHORD.to_vec()], callback));
+        manager
+    }
+
+    fn double_tap(callback: fn()) -> HotkeyManager {
+        let mut manager = HotkeyManager::new(HotkeyBackend::Poll);
+        let steps = vec![vec![Keycode::LControl], vec![Keycode::LControl]];
+        manager.register(Hotkey::sequence(steps, callback));
+        manager
+    }
+
+    #[test]
+    fn held_chord_fires_once() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        let mut manager = chord(|| {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        });
+        let frames: Vec<(u64, &[Keycode])> = (0..40).map(|i| (i * 30, &CHORD[..])).collect();
+        drive(&mut manager, Instant::now(), &frames);
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn repeat_inside_debounce_is_suppressed() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        let mut manager = chord(|| {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        });
+        drive(
+            &mut manager,
+            Instant::now(),
+            &[
+                (0, &CHORD),
+                (30, &[]),
+                (60, &CHORD),
+                (90, &[]),
+                (120, &CHORD),
+            ],
+        );
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn release_and_press_rearms() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        let mut manager = chord(|| {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        });
+        drive(
+            &mut manager,
+            Instant::now(),
+            &[
+                (0, &CHORD),
+                (30, &CHORD),
+                (60, &[Keycode::LControl]),
+                (500, &CHORD),
+                (530, &[]),
+                (1000, &CHORD),
+            ],
+        );
+        assert_eq!(FIRED.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn double_tap_matches_inside_window() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        let mut manager = double_tap(|| {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        });
+        let ctrl: &[Keycode] = &[Keycode::LControl];
+        drive(
+            &mut manager,
+            Instant::now(),
+            &[(0, ctrl), (60, &[]), (150, ctrl), (210, &[])],
+        );
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn double_tap_resets_after_window() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        let mut manager = double_tap(|| {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        });
+        let ctrl: &[Keycode] = &[Keycode::LControl];
+        let start = Instant::now();
+        // 第二次按下太晚, 重新作为第一步
+        drive(
+            &mut manager,
+            start,
+            &[(0, ctrl), (60, &[]), (1000, ctrl), (1060, &[])],
+        );
+        assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+        // 再按一次即可匹配
+        drive(&mut manager, start, &[(1150, ctrl), (1210, &[])]);
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,188 @@
+use super::super::xorg;
+use super::{Hotkey, Keycode};
+use std::os::raw::{c_int, c_uint};
+use std::ptr;
+use std::time::{Duration, Instant};
+use x11::{keysym, xlib};
+
+// 通过 XGrabKey 注册全局快捷键, 只有注册的组合键会发送给本程序
+
+const MODIFIERS: c_uint = xlib::ControlMask | xlib::ShiftMask | xlib::Mod1Mask | xlib::Mod4Mask;
+// CapsLock 和 NumLock 打开时也需要触发
+const IGNORED: [c_uint; 4] = [
+    0,
+    xlib::LockMask,
+    xlib::Mod2Mask,
+    xlib::LockMask | xlib::Mod2Mask,
+];
+
+struct Grab {
+    keycode: c_uint,
+    modifiers: c_uint,
+    callback: fn(),
+    debounce: Duration,
+    fired_at: Option<Instant>,
+    // 按下后到松开前不再触发
+    pressed: bool,
+}
+
+fn modifier(key: Keycode) -> Option<c_uint> {
+    match key {
+        Keycode::LControl | Keycode::RControl => Some(xlib::ControlMask),
+        Keycode::LShift | Keycode::RShift => Some(xlib::ShiftMask),
+        Keycode::LAlt | Keycode::RAlt => Some(xlib::Mod1Mask),
+        Keycode::Meta => Some(xlib::Mod4Mask),
+        _ => None,
+    }
+}
+
+fn keysym(key: Keycode) -> Option<c_uint> {
+    use Keycode::*;
+    const LETTERS: [Keycode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [Keycode; 10] = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    const FUNCTIONS: [Keycode; 12] = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+
+    if let Some(i) = LETTERS.iter().position(|k| *k == key) {
+        return Some(keysym::XK_a + i as c_uint);
+    }
+    if let Some(i) = DIGITS.iter().position(|k| *k == key) {
+        return Some(keysym::XK_0 + i as c_uint);
+    }
+    if let Some(i) = FUNCTIONS.iter().position(|k| *k == key) {
+        return Some(keysym::XK_F1 + i as c_uint);
+    }
+    let sym = match key {
+        Escape => keysym::XK_Escape,
+        Space => keysym::XK_space,
+        Enter => keysym::XK_Return,
+        Tab => keysym::XK_Tab,
+        Backspace => keysym::XK_BackSpace,
+        Insert => keysym::XK_Insert,
+        Delete => keysym::XK_Delete,
+        Home => keysym::XK_Home,
+        End => keysym::XK_End,
+        PageUp => keysym::XK_Prior,
+        PageDown => keysym::XK_Next,
+        Up => keysym::XK_Up,
+        Down => keysym::XK_Down,
+        Left => keysym::XK_Left,
+        Right => keysym::XK_Right,
+        _ => return None,
+    };
+    Some(sym)
+}
+
+// 组合键由修饰键和一个普通键组成
+unsafe fn parse(display: *mut xlib::Display, hotkey: &Hotkey) -> Result<Grab, String> {
+    let keys = match &hotkey.steps[..] {
+        [keys] => keys,
+        _ => return Err("Key sequences are not supported".to_string()),
+    };
+    let mut modifiers = 0;
+    let mut trigger = None;
+    for key in keys.iter() {
+        match modifier(*key) {
+            Some(mask) => modifiers |= mask,
+            None if trigger.is_none() => trigger = Some(*key),
+            None => return Err("Hotkey must contain only one non-modifier key".to_string()),
+        }
+    }
+    let trigger = match trigger {
+        Some(trigger) => trigger,
+        None => return Err("Hotkey must contain a non-modifier key".to_string()),
+    };
+    let sym = match keysym(trigger) {
+        Some(sym) => sym,
+        None => return Err(format!("Key {:?} is not supported", trigger)),
+    };
+    let keycode = xlib::XKeysymToKeycode(display, sym as xlib::KeySym);
+    if keycode == 0 {
+        return Err(format!("Key {:?} is not on the keyboard", trigger));
+    }
+    Ok(Grab {
+        keycode: keycode as c_uint,
+        modifiers,
+        callback: hotkey.callback,
+        debounce: hotkey.debounce,
+        fired_at: None,
+        pressed: false,
+    })
+}
+
+// 注册成功后一直阻塞等待按键
+pub fn listen(hot_keys: &[Hotkey]) -> Result<(), String> {
+    unsafe {
+        let display = xlib::XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err("Could not open X display".to_string());
+        }
+        xorg::take_error(display);
+        // 按住时的自动重复只产生 KeyPress, 不再夹带 KeyRelease
+        xlib::XkbSetDetectableAutoRepeat(display, xlib::True, ptr::null_mut());
+        let root = xlib::XDefaultRootWindow(display);
+
+        let mut grabs = Vec::new();
+        for hotkey in hot_keys.iter() {
+            let grab = match parse(display, hotkey) {
+                Ok(grab) => grab,
+                Err(error) => {
+                    xlib::XCloseDisplay(display);
+                    return Err(error);
+                }
+            };
+            for ignored in IGNORED.iter() {
+                xlib::XGrabKey(
+                    display,
+                    grab.keycode as c_int,
+                    grab.modifiers | ignored,
+                    root,
+                    xlib::False,
+                    xlib::GrabModeAsync,
+                    xlib::GrabModeAsync,
+                );
+            }
+            grabs.push(grab);
+        }
+        xlib::XSync(display, xlib::False);
+        // 其他程序已经注册时 XGrabKey 产生 BadAccess 错误
+        if xorg::take_error(display).is_some() {
+            xlib::XCloseDisplay(display);
+            return Err("Hotkey is already registered by another application".to_string());
+        }
+
+        loop {
+            let mut event: xlib::XEvent = std::mem::zeroed();
+            xlib::XNextEvent(display, &mut event);
+            let kind = event.get_type();
+            if kind != xlib::KeyPress && kind != xlib::KeyRelease {
+                continue;
+            }
+            let key = event.key;
+            let now = Instant::now();
+            for grab in grabs.iter_mut() {
+                if grab.keycode != key.keycode {
+                    continue;
+                }
+                // 松开时修饰键可能已经先松开, 只比较键码
+                if kind == xlib::KeyRelease {
+                    grab.pressed = false;
+                    continue;
+                }
+                if grab.modifiers != key.state & MODIFIERS || grab.pressed {
+                    continue;
+                }
+                grab.pressed = true;
+                // 服务器不支持 detectable auto repeat 时仍由 debounce 过滤重复
+                let repeated = grab
+                    .fired_at
+                    .is_some_and(|t| now.saturating_duration_since(t) < grab.debounce);
+                if !repeated {
+                    grab.fired_at = Some(now);
+                    (grab.callback)();
+                }
+            }
+        }
+    }
+}
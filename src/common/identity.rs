@@ -0,0 +1,31 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+const NODE_ID_FILE: &str = "node_id";
+
+pub fn default_data_dir() -> PathBuf {
+    match dirs::data_dir() {
+        Some(dir) => dir.join("uniclipboard"),
+        None => PathBuf::from(".uniclipboard"),
+    }
+}
+
+// 读取持久化的节点 ID, 不存在时生成新的 UUID 并保存
+pub fn load_node_id(data_dir: &Path) -> String {
+    let path = data_dir.join(NODE_ID_FILE);
+    if let Ok(id) = fs::read_to_string(&path) {
+        if let Ok(id) = Uuid::parse_str(id.trim()) {
+            return id.to_string();
+        }
+        warn!("Invalid node id in {}, regenerating", path.display());
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let res = fs::create_dir_all(data_dir).and_then(|_| fs::write(&path, &id));
+    if let Err(error) = res {
+        warn!("Unable to save node id to {}: {}", path.display(), error);
+    }
+    id
+}
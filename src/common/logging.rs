@@ -0,0 +1,71 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+// 是否在日志中记录剪贴板内容, 默认隐藏
+static SENSITIVE: AtomicBool = AtomicBool::new(false);
+
+// 单独的级别只作用于本程序, 依赖库只输出警告
+fn directive(level: &str) -> String {
+    match level.parse::<LevelFilter>() {
+        Ok(_) => format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level),
+        Err(_) => level.to_string(),
+    }
+}
+
+// level 支持 EnvFilter 的格式, 例如 "debug" 或 "uni_clipboard=trace"
+pub fn init(level: &str, json_file: Option<&Path>, sensitive: bool) -> Result<(), String> {
+    SENSITIVE.store(sensitive, Ordering::SeqCst);
+
+    let level = directive(level);
+    let filter = EnvFilter::try_new(&level)
+        .map_err(|error| format!("Invalid log level \"{}\": {}", level, error))?;
+    // 日志写到 stderr, stdout 留给 recv 等命令的输出
+    let console = fmt::layer()
+        .with_writer(io::stderr)
+        .with_target(false)
+        .with_filter(filter);
+
+    let json = match json_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|error| format!("{}: {}", path.display(), error))?;
+            let filter = EnvFilter::try_new(&level).map_err(|error| format!("{}", error))?;
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_writer(Mutex::new(file))
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(json)
+        .try_init()
+        .map_err(|error| format!("{}", error))
+}
+
+pub fn sensitive() -> bool {
+    SENSITIVE.load(Ordering::SeqCst)
+}
+
+// 隐藏剪贴板内容, 只保留长度
+pub fn redact(text: &str) -> String {
+    if sensitive() {
+        text.to_string()
+    } else {
+        format!("<redacted {} bytes>", text.len())
+    }
+}
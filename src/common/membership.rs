@@ -0,0 +1,301 @@
+use super::super::datatype::{Member, RemoteClipboard};
+use std::collections::HashMap;
+
+// 每个节点的版本号只由节点自己递增, 其他节点只能在相同版本上将其标记为离线.
+// 节点发现自己被标记为离线时, 递增版本号重新声明在线.
+pub struct Membership {
+    local: Member,
+    members: HashMap<String, Member>,
+}
+
+fn newer(a: &Member, b: &Member) -> bool {
+    a.version > b.version || (a.version == b.version && !a.alive && b.alive)
+}
+
+impl Membership {
+    pub fn new(node_id: &str, port: u16) -> Membership {
+        Membership {
+            local: Member {
+                node_id: node_id.to_string(),
+                remote: RemoteClipboard {
+                    host: String::new(),
+                    port,
+                },
+                version: 0,
+                alive: true,
+            },
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.values().cloned().collect();
+        members.push(self.local.clone());
+        members
+    }
+
+    pub fn alive(&self) -> Vec<Member> {
+        self.members.values().filter(|m| m.alive).cloned().collect()
+    }
+
+    // 本节点退出时调用, 返回需要广播的变更
+    pub fn quit(&mut self) -> Member {
+        self.local.version += 1;
+        self.local.alive = false;
+        self.local.clone()
+    }
+
+    // 建立直接连接时调用, 返回需要广播的变更
+    pub fn join(&mut self, node_id: &str, remote: &RemoteClipboard) -> Option<Member> {
+        match self.members.get_mut(node_id) {
+            Some(member) => {
+                if member.alive && member.remote != *remote {
+                    member.remote = remote.clone();
+                }
+                // 离线的节点需要自己递增版本号后才会重新上线
+                None
+            }
+            None => {
+                let member = Member {
+                    node_id: node_id.to_string(),
+                    remote: remote.clone(),
+                    version: 0,
+                    alive: true,
+                };
+                self.members.insert(node_id.to_string(), member.clone());
+                Some(member)
+            }
+        }
+    }
+
+    // 直接连接断开时调用, 返回需要广播的变更
+    pub fn leave(&mut self, node_id: &str) -> Option<Member> {
+        match self.members.get_mut(node_id) {
+            Some(member) if member.alive => {
+                member.alive = false;
+                Some(member.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // 合并其他节点发来的信息, 返回需要继续广播的变更
+    pub fn merge(&mut self, mut member: Member) -> Option<Member> {
+        if member.node_id == self.local.node_id {
+            if self.local.alive && !member.alive && member.version >= self.local.version {
+                self.local.version = member.version + 1;
+                return Some(self.local.clone());
+            }
+            return None;
+        }
+
+        match self.members.get(&member.node_id) {
+            Some(old) if !newer(&member, old) => None,
+            old => {
+                if member.remote.host.is_empty() {
+                    if let Some(old) = old {
+                        member.remote.host = old.remote.host.clone();
+                    }
+                }
+                self.members.insert(member.node_id.clone(), member.clone());
+                Some(member)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // 进程内的多个节点, 变更只沿直接连接传播
+    struct Cluster {
+        nodes: Vec<Membership>,
+        links: Vec<(usize, usize)>,
+    }
+
+    fn remote(i: usize) -> RemoteClipboard {
+        RemoteClipboard {
+            host: "127.0.0.1".to_string(),
+            port: 11000 + i as u16,
+        }
+    }
+
+    fn id(i: usize) -> String {
+        format!("node-{}", i)
+    }
+
+    impl Cluster {
+        fn new(count: usize) -> Cluster {
+            Cluster {
+                nodes: (0..count)
+                    .map(|i| Membership::new(&id(i), remote(i).port))
+                    .collect(),
+                links: Vec::new(),
+            }
+        }
+
+        fn neighbors(&self, i: usize) -> Vec<usize> {
+            self.links
+                .iter()
+                .filter_map(|&(a, b)| match i {
+                    _ if a == i => Some(b),
+                    _ if b == i => Some(a),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        // 把 from 节点的变更发给它的邻居, 直到没有新的变更
+        fn spread(&mut self, from: usize, changes: Vec<Member>) {
+            let mut queue: VecDeque<(usize, Member)> =
+                changes.into_iter().map(|m| (from, m)).collect();
+            while let Some((from, change)) = queue.pop_front() {
+                for to in self.neighbors(from) {
+                    if let Some(next) = self.nodes[to].merge(change.clone()) {
+                        queue.push_back((to, next));
+                    }
+                }
+            }
+        }
+
+        // 和 add_handler 相同: 双方记录对方, 发送完整列表, 其他节点获取变更
+        fn connect(&mut self, a: usize, b: usize) {
+            self.links.push((a, b));
+            for (x, y) in [(a, b), (b, a)] {
+                if let Some(change) = self.nodes[x].join(&id(y), &remote(y)) {
+                    self.spread(x, vec![change]);
+                }
+                for member in self.nodes[x].snapshot() {
+                    if let Some(change) = self.nodes[y].merge(member) {
+                        self.spread(y, vec![change]);
+                    }
+                }
+            }
+        }
+
+        // 返回双方产生的离线消息
+        fn disconnect(&mut self, a: usize, b: usize) -> Vec<Member> {
+            self.links.retain(|&link| link != (a, b) && link != (b, a));
+            let mut changes = Vec::new();
+            for (x, y) in [(a, b), (b, a)] {
+                if let Some(change) = self.nodes[x].leave(&id(y)) {
+                    self.spread(x, vec![change.clone()]);
+                    changes.push(change);
+                }
+            }
+            changes
+        }
+
+        fn quit(&mut self, i: usize) {
+            let change = self.nodes[i].quit();
+            self.spread(i, vec![change]);
+            let links: Vec<usize> = self.neighbors(i);
+            self.links.retain(|&(a, b)| a != i && b != i);
+            for j in links {
+                self.nodes[j].leave(&id(i));
+            }
+        }
+
+        // 节点看到的 (节点, 版本, 是否在线), 不含地址
+        fn view(&self, i: usize) -> Vec<(String, u64, bool)> {
+            let mut view: Vec<(String, u64, bool)> = self.nodes[i]
+                .snapshot()
+                .into_iter()
+                .map(|m| (m.node_id, m.version, m.alive))
+                .collect();
+            view.sort();
+            view
+        }
+
+        fn assert_converged(&self) {
+            for i in 1..self.nodes.len() {
+                assert_eq!(self.view(0), self.view(i), "node {} differs", i);
+            }
+        }
+
+        fn is_alive(&self, i: usize, node: usize) -> bool {
+            self.nodes[i].alive().iter().any(|m| m.node_id == id(node))
+        }
+    }
+
+    #[test]
+    fn chain_converges() {
+        let mut cluster = Cluster::new(4);
+        cluster.connect(0, 1);
+        cluster.connect(1, 2);
+        cluster.connect(2, 3);
+        cluster.assert_converged();
+        assert_eq!(cluster.view(0).len(), 4);
+        for i in 0..4 {
+            assert_eq!(cluster.nodes[i].alive().len(), 3);
+        }
+    }
+
+    #[test]
+    fn quit_converges() {
+        let mut cluster = Cluster::new(3);
+        cluster.connect(0, 1);
+        cluster.connect(1, 2);
+        cluster.quit(2);
+        cluster.assert_converged();
+        assert!(!cluster.is_alive(0, 2));
+        assert!(!cluster.is_alive(1, 2));
+    }
+
+    #[test]
+    fn stale_gossip_does_not_resurrect() {
+        let mut cluster = Cluster::new(3);
+        cluster.connect(0, 1);
+        cluster.connect(1, 2);
+        let stale: Vec<Member> = cluster.nodes[2].snapshot();
+        cluster.quit(2);
+
+        // 退出前的旧信息版本更低, 不能让节点重新上线
+        for member in stale {
+            assert!(cluster.nodes[0].merge(member.clone()).is_none());
+            assert!(cluster.nodes[1].merge(member).is_none());
+        }
+        assert!(!cluster.is_alive(0, 2));
+        assert!(!cluster.is_alive(1, 2));
+        cluster.assert_converged();
+    }
+
+    #[test]
+    fn refuted_leave_is_not_undone() {
+        let mut cluster = Cluster::new(3);
+        cluster.connect(0, 1);
+        cluster.connect(1, 2);
+        cluster.connect(0, 2);
+
+        // 0 和 2 之间断开, 2 经 1 收到自己离线的消息后递增版本号
+        let changes = cluster.disconnect(0, 2);
+        let stale = changes.into_iter().find(|m| m.node_id == id(2)).unwrap();
+        assert!(!stale.alive);
+        cluster.assert_converged();
+        assert!(cluster.is_alive(0, 2));
+
+        // 延迟到达的旧离线消息不能再次让节点离线
+        assert!(cluster.nodes[0].merge(stale.clone()).is_none());
+        assert!(cluster.nodes[1].merge(stale).is_none());
+        assert!(cluster.is_alive(0, 2));
+        cluster.assert_converged();
+    }
+
+    #[test]
+    fn rejoin_after_quit_needs_new_version() {
+        let mut cluster = Cluster::new(3);
+        cluster.connect(0, 1);
+        cluster.connect(1, 2);
+        cluster.quit(2);
+
+        // 重启的节点版本号从 0 开始, 收到自己离线的消息后递增
+        cluster.nodes[2] = Membership::new(&id(2), remote(2).port);
+        cluster.connect(2, 0);
+        cluster.assert_converged();
+        for i in 0..2 {
+            assert!(cluster.is_alive(i, 2));
+        }
+    }
+}
@@ -1,19 +1,18 @@
 use super::super::datatype::{
-    UniclipDataFrame, UniclipPayload, UNICLIP_MAGIC, UNICLIP_PROTO_VERSION,
+    ClipboardEntry, UniclipDataFrame, UniclipFrameHeader, UniclipPayload, UNICLIP_MAGIC,
+    UNICLIP_PROTO_VERSION,
 };
-use super::message;
 use hex::decode;
 use serde_encrypt::{shared_key::SharedKey, traits::SerdeEncryptSharedKey, EncryptedMessage};
-use sha256::digest;
+use sha256::{digest, digest_bytes};
+use tracing::{error, trace};
 
 pub fn pwd2key(password: String) -> SharedKey {
     let val = digest(password);
     let val = decode(val).unwrap();
 
     let mut hash = [0u8; 32];
-    for i in 0..32 {
-        hash[i] = val[i];
-    }
+    hash.copy_from_slice(&val[..32]);
     SharedKey::new(hash)
 }
 
@@ -25,41 +24,92 @@ pub fn pack(data: UniclipPayload, key: &SharedKey) -> Vec<u8> {
     };
     let encrypted_data = data_frame.encrypt(key).unwrap();
     let serialized_data = encrypted_data.serialize();
-    println!("SEND DATA: {:?}", serialized_data);
+    trace!(bytes = serialized_data.len(), "Send data frame");
     serialized_data
 }
 
 pub fn unpack(data: Vec<u8>, key: &SharedKey) -> UniclipPayload {
-    println!("RECV DATA: {:?}", data);
-    let encrypted_data = EncryptedMessage::deserialize(data).unwrap();
+    trace!(bytes = data.len(), "Receive data frame");
+    let encrypted_data = match EncryptedMessage::deserialize(data) {
+        Ok(data) => data,
+        Err(_) => {
+            error!("UNPACK ERROR, Invalid data frame");
+            return UniclipPayload::Error(String::from("Invalid data frame"));
+        }
+    };
     let data_frame = UniclipDataFrame::decrypt_owned(&encrypted_data, key);
     match data_frame {
         Ok(data) => {
             if data.magic != UNICLIP_MAGIC {
-                message::error(String::from("UNPACK ERROR, Invalid magic number"));
-                return UniclipPayload::Error(String::from("Invalid magic number"));
+                error!("UNPACK ERROR, Invalid magic number");
+                UniclipPayload::Error(String::from("Invalid magic number"))
             } else if data.version != UNICLIP_PROTO_VERSION {
-                message::error(String::from("UNPACK ERROR, Invalid protocol version"));
-                return UniclipPayload::Error(String::from("Invalid protocol version"));
+                error!(
+                    "UNPACK ERROR, Unsupported protocol version {}",
+                    data.version
+                );
+                UniclipPayload::Error(version_error(data.version))
             } else {
-                return data.payload;
+                data.payload
             }
         }
-        Err(_) => {
-            message::error(String::from("UNPACK ERROR, Unable to decrypt data frame"));
-            return UniclipPayload::Error(String::from("Unable to decrypt data frame"));
-        }
+        Err(_) => match UniclipFrameHeader::decrypt_owned(&encrypted_data, key) {
+            // 密钥正确但负载格式不同
+            Ok(header)
+                if header.magic == UNICLIP_MAGIC && header.version != UNICLIP_PROTO_VERSION =>
+            {
+                error!(
+                    "UNPACK ERROR, Unsupported protocol version {}",
+                    header.version
+                );
+                UniclipPayload::Error(version_error(header.version))
+            }
+            _ => {
+                error!("UNPACK ERROR, Unable to decrypt data frame");
+                UniclipPayload::Error(String::from("Unable to decrypt data frame"))
+            }
+        },
     }
 }
 
+fn version_error(version: u8) -> String {
+    format!(
+        "Unsupported protocol version {}, this node uses version {}",
+        version, UNICLIP_PROTO_VERSION
+    )
+}
+
 pub trait Hash<T> {
     fn hash(data: T) -> String;
 }
 
 impl Hash<&Vec<u8>> for &Vec<u8> {
     fn hash(data: &Vec<u8>) -> String {
-        let data = std::str::from_utf8(data).unwrap();
-        digest(data)
+        digest_bytes(data)
+    }
+}
+
+// 只有一种格式时与该格式内容的 hash 相同, 多种格式时包含所有格式
+impl Hash<&ClipboardEntry> for &ClipboardEntry {
+    fn hash(entry: &ClipboardEntry) -> String {
+        match &entry.formats[..] {
+            [(_, content)] => digest_bytes(content),
+            formats => {
+                let mut buf = Vec::new();
+                for (mime, content) in formats.iter() {
+                    buf.extend_from_slice(mime.as_bytes());
+                    buf.extend_from_slice(&(content.len() as u64).to_be_bytes());
+                    buf.extend_from_slice(content);
+                }
+                digest_bytes(&buf)
+            }
+        }
+    }
+}
+
+impl Hash<&[u8]> for &[u8] {
+    fn hash(data: &[u8]) -> String {
+        digest_bytes(data)
     }
 }
 
@@ -72,3 +122,47 @@ impl Hash<&String> for &String {
 pub fn hash<T: Hash<T>>(data: T) -> String {
     T::hash(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = pwd2key("secret".to_string());
+        match unpack(pack(UniclipPayload::Echo(7), &key), &key) {
+            UniclipPayload::Echo(7) => (),
+            payload => panic!("unexpected {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        let key = pwd2key("secret".to_string());
+        let frame = UniclipDataFrame {
+            magic: UNICLIP_MAGIC,
+            version: UNICLIP_PROTO_VERSION.wrapping_add(1),
+            payload: UniclipPayload::Echo(7),
+        };
+        let data = frame.encrypt(&key).unwrap().serialize();
+        match unpack(data, &key) {
+            UniclipPayload::Error(error) => assert!(error.contains("protocol version")),
+            payload => panic!("unexpected {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn rejects_other_version_with_unknown_payload() {
+        // 只有头部的数据帧, 相当于负载无法解析的其他版本
+        let key = pwd2key("secret".to_string());
+        let header = UniclipFrameHeader {
+            magic: UNICLIP_MAGIC,
+            version: UNICLIP_PROTO_VERSION.wrapping_add(1),
+        };
+        let data = header.encrypt(&key).unwrap().serialize();
+        match unpack(data, &key) {
+            UniclipPayload::Error(error) => assert!(error.contains("protocol version")),
+            payload => panic!("unexpected {:?}", payload),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use super::super::datatype::EntryInfo;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+// 未完成的分块传输保存在数据目录中, 断线重连后从中断的位置继续.
+// 文件中依次保存条目各格式的内容, 以内容 hash 和条目 ID 命名.
+// 敏感的条目只保存在内存中, 断线后不能继续
+
+// 超过该时间的未完成传输在启动时删除
+const PARTIAL_EXPIRE: time::Duration = time::Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    static ref DIR: RwLock<PathBuf> = RwLock::new(PathBuf::new());
+    // 正在接收的传输, 同一条目不能同时从多个连接接收
+    static ref ACTIVE: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+pub fn init(dir: PathBuf) {
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let expired = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default() > PARTIAL_EXPIRE)
+                .unwrap_or(true);
+            if expired {
+                debug!("Remove expired partial transfer {}", entry.path().display());
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    *DIR.write().unwrap() = dir;
+    ACTIVE.lock().unwrap().clear();
+}
+
+// hash 和条目 ID 由对方发送, 只接受 SHA-256 的十六进制形式和带连字符的 UUID,
+// 防止写到目录之外
+pub fn valid(hash: &str, entry_id: &str) -> bool {
+    hash.len() == 64
+        && hash.bytes().all(|c| c.is_ascii_hexdigit())
+        && entry_id.len() == 36
+        && Uuid::parse_str(entry_id).is_ok()
+}
+
+fn name(hash: &str, entry_id: &str) -> Option<String> {
+    if !valid(hash, entry_id) {
+        return None;
+    }
+    Some(format!("{}.{}", hash, entry_id))
+}
+
+fn path(name: &str) -> PathBuf {
+    DIR.read().unwrap().join(name)
+}
+
+// 是否有可以继续的传输
+pub fn exists(hash: &str, entry_id: &str) -> bool {
+    let name = match name(hash, entry_id) {
+        Some(name) => name,
+        None => return false,
+    };
+    !ACTIVE.lock().unwrap().contains(&name) && path(&name).is_file()
+}
+
+enum Data {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+pub struct Partial {
+    name: String,
+    data: Data,
+    chunks: Vec<usize>,  // length of each chunk
+    next: usize,         // index of the next chunk
+    pub size: usize,     // total length of the data
+    pub received: usize, // bytes already received
+}
+
+// 打开已有的传输并截断到完整的分块, 没有时新建
+pub fn open(
+    hash: &str,
+    entry_id: &str,
+    chunks: Vec<usize>,
+    sensitive: bool,
+) -> Result<Partial, String> {
+    let name = match name(hash, entry_id) {
+        Some(name) => name,
+        None => return Err("Invalid update id".to_string()),
+    };
+    if !ACTIVE.lock().unwrap().insert(name.clone()) {
+        return Err("Update is already being received".to_string());
+    }
+    if sensitive {
+        return Ok(Partial {
+            name,
+            data: Data::Memory(Vec::new()),
+            size: chunks.iter().sum(),
+            chunks,
+            next: 0,
+            received: 0,
+        });
+    }
+    let path = path(&name);
+    let res = fs::create_dir_all(path.parent().unwrap()).and_then(|_| {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let length = file.metadata()?.len() as usize;
+        let mut next = 0;
+        let mut received = 0;
+        while next < chunks.len() && received + chunks[next] <= length {
+            received += chunks[next];
+            next += 1;
+        }
+        file.set_len(received as u64)?;
+        Ok((file, next, received))
+    });
+    match res {
+        Ok((file, next, received)) => Ok(Partial {
+            name,
+            data: Data::File(file),
+            size: chunks.iter().sum(),
+            chunks,
+            next,
+            received,
+        }),
+        Err(error) => {
+            ACTIVE.lock().unwrap().remove(&name);
+            Err(format!("{}: {}", path.display(), error))
+        }
+    }
+}
+
+impl Partial {
+    // 下一个需要的分块序号
+    pub fn next_chunk(&self) -> usize {
+        self.next
+    }
+
+    pub fn complete(&self) -> bool {
+        self.next == self.chunks.len()
+    }
+
+    pub fn write(&mut self, index: usize, chunk: &[u8]) -> Result<(), String> {
+        if index != self.next {
+            return Err(format!("Received chunk {}, expected {}", index, self.next));
+        }
+        if chunk.len() != self.chunks[index] {
+            return Err(format!("Chunk {} length error", index));
+        }
+        match &mut self.data {
+            Data::File(file) => file
+                .write_all(chunk)
+                .map_err(|error| format!("{}: {}", self.name, error))?,
+            Data::Memory(data) => data.extend_from_slice(chunk),
+        }
+        self.next += 1;
+        self.received += chunk.len();
+        Ok(())
+    }
+
+    // 依次读取各格式的内容并删除文件
+    pub fn finish(mut self, info: &EntryInfo) -> Result<Vec<Vec<u8>>, String> {
+        if !self.complete() {
+            self.discard();
+            return Err("Incomplete update data".to_string());
+        }
+        let res = match &mut self.data {
+            Data::File(_) => File::open(path(&self.name)).and_then(|file| read_formats(file, info)),
+            Data::Memory(data) => read_formats(io::Cursor::new(std::mem::take(data)), info),
+        };
+        let res = res.map_err(|error| format!("{}: {}", self.name, error));
+        self.discard();
+        res
+    }
+
+    // 取消或出错时删除已收到的数据
+    pub fn discard(self) {
+        if let Data::File(_) = self.data {
+            let path = path(&self.name);
+            if let Err(error) = fs::remove_file(&path) {
+                error!("{}: {}", path.display(), error);
+            }
+        }
+    }
+}
+
+fn read_formats(mut reader: impl Read, info: &EntryInfo) -> io::Result<Vec<Vec<u8>>> {
+    let mut contents = Vec::new();
+    for (_, size) in info.formats.iter() {
+        let mut data = vec![0u8; *size];
+        reader.read_exact(&mut data)?;
+        contents.push(data);
+    }
+    Ok(contents)
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.name);
+    }
+}
@@ -0,0 +1,425 @@
+use super::super::datatype::{
+    ConfirmRule, EntryInfo, PeerRule, RateLimit, ReceivePolicy, RemoteClipboard, SizeLimit,
+    SyncMode, SyncPolicy, TypeAllow,
+};
+use std::str::FromStr;
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "both" | "bidirectional" => Ok(SyncMode::Bidirectional),
+            "send" | "send-only" => Ok(SyncMode::SendOnly),
+            "recv" | "receive" | "receive-only" => Ok(SyncMode::ReceiveOnly),
+            "off" | "disabled" => Ok(SyncMode::Disabled),
+            _ => Err(format!("Invalid sync mode \"{}\"", s)),
+        }
+    }
+}
+
+impl SyncMode {
+    pub fn can_send(&self) -> bool {
+        matches!(self, SyncMode::Bidirectional | SyncMode::SendOnly)
+    }
+
+    pub fn can_recv(&self) -> bool {
+        matches!(self, SyncMode::Bidirectional | SyncMode::ReceiveOnly)
+    }
+}
+
+// 规则格式: <peer>=<mode>, peer 可以是节点 ID, host 或 host:port
+impl FromStr for PeerRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((target, mode)) if !target.is_empty() => Ok(PeerRule {
+                target: target.to_string(),
+                mode: mode.parse()?,
+            }),
+            _ => Err(format!("Invalid sync rule \"{}\"", s)),
+        }
+    }
+}
+
+fn matches_peer(target: &str, node_id: &str, remote: &RemoteClipboard) -> bool {
+    target == node_id || target == remote.host || target == remote.to_string()
+}
+
+impl PeerRule {
+    pub fn matches(&self, node_id: &str, remote: &RemoteClipboard) -> bool {
+        matches_peer(&self.target, node_id, remote)
+    }
+}
+
+// 大小可以带单位 K, M, G, 例如 512K, 10M
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit.to_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid size \"{}\"", s)),
+    };
+    match number
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+    {
+        Some(size) => Ok(size),
+        None => Err(format!("Invalid size \"{}\"", s)),
+    }
+}
+
+// 接收规则可以用 @<peer> 指定节点
+fn split_peer(s: &str) -> (&str, Option<String>) {
+    match s.rsplit_once('@') {
+        Some((rule, peer)) if !peer.is_empty() => (rule, Some(peer.to_string())),
+        _ => (s, None),
+    }
+}
+
+fn valid_mime(mime: &str) -> bool {
+    mime == "*"
+        || mime
+            .split_once('/')
+            .is_some_and(|(a, b)| !a.is_empty() && !b.is_empty())
+}
+
+// 格式: <mime>=<size>[@<peer>], 例如 image/*=10M
+impl FromStr for SizeLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, peer) = split_peer(s);
+        match rule.split_once('=') {
+            Some((mime, size)) if valid_mime(mime) => Ok(SizeLimit {
+                mime: mime.to_lowercase(),
+                size: parse_size(size)?,
+                peer,
+            }),
+            _ => Err(format!("Invalid size limit \"{}\"", s)),
+        }
+    }
+}
+
+// 格式: <mime>[@<peer>], 例如 text/*
+impl FromStr for TypeAllow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mime, peer) = split_peer(s);
+        if !valid_mime(mime) {
+            return Err(format!("Invalid content type \"{}\"", s));
+        }
+        Ok(TypeAllow {
+            mime: mime.to_lowercase(),
+            peer,
+        })
+    }
+}
+
+// 格式: <size>[@<peer>]
+impl FromStr for ConfirmRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, peer) = split_peer(s);
+        Ok(ConfirmRule {
+            size: parse_size(size)?,
+            peer,
+        })
+    }
+}
+
+// 格式: <rate>[@<peer>], 例如 1M, 256K@host, 速率为每秒字节数
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, peer) = split_peer(s);
+        match parse_size(rate)? {
+            0 => Err(format!("Invalid rate \"{}\"", s)),
+            rate => Ok(RateLimit { rate, peer }),
+        }
+    }
+}
+
+impl RateLimit {
+    // 只匹配针对节点的规则, * 匹配每个节点
+    pub fn matches(&self, node_id: &str, remote: &RemoteClipboard) -> bool {
+        match &self.peer {
+            Some(target) => target == "*" || matches_peer(target, node_id, remote),
+            None => false,
+        }
+    }
+}
+
+// * 匹配所有类型, image/* 匹配所有图片
+fn matches_mime(pattern: &str, mime: &str) -> bool {
+    let mime = mime.to_lowercase();
+    match pattern.strip_suffix("/*") {
+        _ if pattern == "*" => true,
+        Some(prefix) => mime.split_once('/').is_some_and(|(kind, _)| kind == prefix),
+        None => pattern == mime,
+    }
+}
+
+fn for_peer(peer: &Option<String>, node_id: &str, remote: &RemoteClipboard) -> bool {
+    match peer {
+        Some(target) => matches_peer(target, node_id, remote),
+        None => true,
+    }
+}
+
+impl ReceivePolicy {
+    // 有针对该节点的白名单时只使用这些规则, 没有任何白名单时允许所有类型
+    fn allows(&self, node_id: &str, remote: &RemoteClipboard, mime: &str) -> bool {
+        let specific: Vec<&TypeAllow> = self
+            .allow
+            .iter()
+            .filter(|rule| rule.peer.is_some() && for_peer(&rule.peer, node_id, remote))
+            .collect();
+        let rules = if specific.is_empty() {
+            self.allow
+                .iter()
+                .filter(|rule| rule.peer.is_none())
+                .collect()
+        } else {
+            specific
+        };
+        rules.is_empty() || rules.iter().any(|rule| matches_mime(&rule.mime, mime))
+    }
+
+    // 最后一条匹配的规则生效
+    fn limit(&self, node_id: &str, remote: &RemoteClipboard, mime: &str) -> Option<usize> {
+        self.limits
+            .iter()
+            .rev()
+            .find(|rule| for_peer(&rule.peer, node_id, remote) && matches_mime(&rule.mime, mime))
+            .map(|rule| rule.size)
+    }
+
+    pub fn confirm_size(&self, node_id: &str, remote: &RemoteClipboard) -> Option<usize> {
+        self.confirm
+            .iter()
+            .rev()
+            .find(|rule| for_peer(&rule.peer, node_id, remote))
+            .map(|rule| rule.size)
+    }
+
+    // 返回可以接收的格式, 一种都不能接收时返回原因
+    pub fn check(
+        &self,
+        node_id: &str,
+        remote: &RemoteClipboard,
+        info: &EntryInfo,
+    ) -> Result<Vec<String>, String> {
+        let mut accepted = Vec::new();
+        let mut reason = "Empty entry".to_string();
+        for (mime, size) in info.formats.iter() {
+            if !self.allows(node_id, remote, mime) {
+                reason = format!("{} is not allowed", mime);
+                continue;
+            }
+            match self.limit(node_id, remote, mime) {
+                Some(limit) if *size > limit => {
+                    reason = format!(
+                        "{} ({} bytes) exceeds the limit of {} bytes",
+                        mime, size, limit
+                    );
+                }
+                _ => accepted.push(mime.clone()),
+            }
+        }
+        if accepted.is_empty() {
+            return Err(reason);
+        }
+        Ok(accepted)
+    }
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy {
+            default_mode: SyncMode::Bidirectional,
+            groups: Vec::new(),
+            rules: Vec::new(),
+            receive: ReceivePolicy::default(),
+        }
+    }
+}
+
+impl SyncPolicy {
+    pub fn mode_for(&self, node_id: &str, remote: &RemoteClipboard) -> SyncMode {
+        // 最后一条匹配的规则生效
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(node_id, remote))
+            .map(|rule| rule.mode)
+            .unwrap_or(self.default_mode)
+    }
+
+    // 本地未设置分组时与所有节点同步, 否则对方需要至少拥有一个相同的分组
+    pub fn shares_group(&self, groups: &[String]) -> bool {
+        self.groups.is_empty() || self.groups.iter().any(|g| groups.contains(g))
+    }
+
+    pub fn can_send(&self, node_id: &str, remote: &RemoteClipboard, groups: &[String]) -> bool {
+        self.mode_for(node_id, remote).can_send() && self.shares_group(groups)
+    }
+
+    pub fn can_recv(&self, node_id: &str, remote: &RemoteClipboard, groups: &[String]) -> bool {
+        self.mode_for(node_id, remote).can_recv() && self.shares_group(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(port: u16) -> RemoteClipboard {
+        RemoteClipboard {
+            host: "10.0.0.2".to_string(),
+            port,
+        }
+    }
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn policy(rules: &[&str]) -> SyncPolicy {
+        SyncPolicy {
+            rules: rules.iter().map(|rule| rule.parse().unwrap()).collect(),
+            ..SyncPolicy::default()
+        }
+    }
+
+    #[test]
+    fn parse_sync_mode_and_rule() {
+        assert_eq!("both".parse(), Ok(SyncMode::Bidirectional));
+        assert_eq!("Send-Only".parse(), Ok(SyncMode::SendOnly));
+        assert_eq!("receive".parse(), Ok(SyncMode::ReceiveOnly));
+        assert_eq!("off".parse(), Ok(SyncMode::Disabled));
+        assert!("sometimes".parse::<SyncMode>().is_err());
+
+        // IPv6 地址本身带 :, 只按最后一个 = 拆分
+        assert_eq!(
+            "[::1]:10500=recv".parse(),
+            Ok(PeerRule {
+                target: "[::1]:10500".to_string(),
+                mode: SyncMode::ReceiveOnly,
+            })
+        );
+        assert!("=both".parse::<PeerRule>().is_err());
+        assert!("host".parse::<PeerRule>().is_err());
+        assert!("host=maybe".parse::<PeerRule>().is_err());
+    }
+
+    #[test]
+    fn parse_receive_rules() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4 * 1024));
+        assert_eq!(parse_size("10MB"), Ok(10 * 1024 * 1024));
+        assert!(parse_size("10T").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX)).is_err());
+
+        assert_eq!(
+            "Image/*=10M@laptop".parse(),
+            Ok(SizeLimit {
+                mime: "image/*".to_string(),
+                size: 10 * 1024 * 1024,
+                peer: Some("laptop".to_string()),
+            })
+        );
+        assert!("image=10M".parse::<SizeLimit>().is_err());
+        assert!("*".parse::<SizeLimit>().is_err());
+
+        assert_eq!(
+            "text/*".parse(),
+            Ok(TypeAllow {
+                mime: "text/*".to_string(),
+                peer: None,
+            })
+        );
+        assert!("text".parse::<TypeAllow>().is_err());
+
+        assert_eq!(
+            "1M".parse(),
+            Ok(ConfirmRule {
+                size: 1024 * 1024,
+                peer: None,
+            })
+        );
+        assert!("1M@".parse::<ConfirmRule>().is_err());
+        assert!("0@host".parse::<RateLimit>().is_err());
+        assert_eq!("256K@*".parse::<RateLimit>().unwrap().rate, 256 * 1024);
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let policy = policy(&["10.0.0.2=send", "10.0.0.2:10500=off", "node-b=recv"]);
+        assert_eq!(
+            policy.mode_for("node-a", &remote(10500)),
+            SyncMode::Disabled
+        );
+        assert_eq!(
+            policy.mode_for("node-a", &remote(10501)),
+            SyncMode::SendOnly
+        );
+        assert_eq!(
+            policy.mode_for("node-b", &remote(10500)),
+            SyncMode::ReceiveOnly
+        );
+
+        let other = RemoteClipboard {
+            host: "10.0.0.3".to_string(),
+            port: 10500,
+        };
+        assert_eq!(policy.mode_for("node-c", &other), SyncMode::Bidirectional);
+        let policy = SyncPolicy {
+            default_mode: SyncMode::Disabled,
+            ..policy
+        };
+        assert_eq!(policy.mode_for("node-c", &other), SyncMode::Disabled);
+    }
+
+    #[test]
+    fn groups_must_overlap() {
+        let mut policy = SyncPolicy::default();
+        assert!(policy.shares_group(&[]));
+        assert!(policy.shares_group(&groups(&["work"])));
+
+        policy.groups = groups(&["work", "home"]);
+        assert!(policy.shares_group(&groups(&["lab", "home"])));
+        assert!(!policy.shares_group(&groups(&["lab"])));
+        assert!(!policy.shares_group(&[]));
+    }
+
+    #[test]
+    fn send_and_recv_follow_mode_and_groups() {
+        let mut policy = policy(&["10.0.0.2=send"]);
+        let work = groups(&["work"]);
+        assert!(policy.can_send("node-a", &remote(10500), &work));
+        assert!(!policy.can_recv("node-a", &remote(10500), &work));
+
+        policy.rules = vec!["10.0.0.2=recv".parse().unwrap()];
+        assert!(!policy.can_send("node-a", &remote(10500), &work));
+        assert!(policy.can_recv("node-a", &remote(10500), &work));
+
+        // 模式允许但没有相同的分组
+        policy.groups = groups(&["home"]);
+        assert!(!policy.can_recv("node-a", &remote(10500), &work));
+        assert!(policy.can_recv("node-a", &remote(10500), &groups(&["home"])));
+    }
+}
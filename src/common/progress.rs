@@ -0,0 +1,224 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time;
+use tracing::{info, warn};
+
+// 两次进度通知的最小间隔
+const NOTIFY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    Running,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+// 分块传输的进度, 同一条消息发给多个节点时每个节点一条
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub id: String,      // message id
+    pub node_id: String, // node id of the peer
+    pub sending: bool,
+    pub done: usize,   // bytes sent or received
+    pub offset: usize, // bytes done before resuming
+    pub size: usize,
+    pub started: time::Instant,
+    pub state: TransferState,
+}
+
+impl Progress {
+    // 每秒字节数
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.done.saturating_sub(self.offset) as f64 / elapsed
+    }
+
+    pub fn eta(&self) -> Option<time::Duration> {
+        let rate = self.rate();
+        if rate <= 0.0 {
+            return None;
+        }
+        let left = self.size.saturating_sub(self.done) as f64;
+        Some(time::Duration::from_secs_f64(left / rate))
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.size == 0 {
+            return 100.0;
+        }
+        self.done as f64 * 100.0 / self.size as f64
+    }
+}
+
+struct Tracker {
+    progress: Progress,
+    cancel: Arc<AtomicBool>,
+    notified: time::Instant,
+}
+
+lazy_static! {
+    static ref TRACKERS: Mutex<HashMap<String, Tracker>> = Mutex::new(HashMap::new());
+    static ref CALLBACKS: RwLock<Vec<fn(&Progress)>> = RwLock::new(Vec::new());
+}
+
+fn key(id: &str, node_id: &str) -> String {
+    format!("{} {}", id, node_id)
+}
+
+// 只清除传输, 已注册的回调保留
+pub fn reset() {
+    TRACKERS.lock().unwrap().clear();
+}
+
+// 开始, 结束和运行中每秒调用一次
+pub fn on_progress(callback: fn(&Progress)) {
+    CALLBACKS.write().unwrap().push(callback);
+}
+
+fn notify(progress: &Progress) {
+    for callback in CALLBACKS.read().unwrap().iter() {
+        callback(progress);
+    }
+}
+
+// 开始一次传输, offset 为继续传输时已完成的字节数, 返回取消标志
+pub fn start(
+    id: &str,
+    node_id: &str,
+    sending: bool,
+    offset: usize,
+    size: usize,
+) -> Arc<AtomicBool> {
+    let now = time::Instant::now();
+    let tracker = Tracker {
+        progress: Progress {
+            id: id.to_string(),
+            node_id: node_id.to_string(),
+            sending,
+            done: offset,
+            offset,
+            size,
+            started: now,
+            state: TransferState::Running,
+        },
+        cancel: Arc::new(AtomicBool::new(false)),
+        notified: now,
+    };
+    let (progress, cancel) = (tracker.progress.clone(), tracker.cancel.clone());
+    TRACKERS.lock().unwrap().insert(key(id, node_id), tracker);
+    notify(&progress);
+    cancel
+}
+
+pub fn advance(id: &str, node_id: &str, bytes: usize) {
+    let progress = {
+        let mut trackers = TRACKERS.lock().unwrap();
+        let tracker = match trackers.get_mut(&key(id, node_id)) {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        tracker.progress.done += bytes;
+        if tracker.notified.elapsed() < NOTIFY_INTERVAL {
+            return;
+        }
+        tracker.notified = time::Instant::now();
+        tracker.progress.clone()
+    };
+    notify(&progress);
+}
+
+pub fn finish(id: &str, node_id: &str, state: TransferState) {
+    let tracker = TRACKERS.lock().unwrap().remove(&key(id, node_id));
+    if let Some(mut tracker) = tracker {
+        tracker.progress.state = state;
+        notify(&tracker.progress);
+    }
+}
+
+// 设置取消标志, 传输不存在时返回 None
+pub fn cancel(id: &str, node_id: &str) -> Option<Progress> {
+    let trackers = TRACKERS.lock().unwrap();
+    let tracker = trackers.get(&key(id, node_id))?;
+    tracker.cancel.store(true, Ordering::SeqCst);
+    Some(tracker.progress.clone())
+}
+
+// 传输存在且没有取消
+pub fn running(id: &str, node_id: &str) -> bool {
+    TRACKERS
+        .lock()
+        .unwrap()
+        .get(&key(id, node_id))
+        .is_some_and(|tracker| !tracker.cancel.load(Ordering::SeqCst))
+}
+
+// 节点断开后结束它的所有传输
+pub fn drop_peer(node_id: &str) {
+    let keys: Vec<(String, String)> = TRACKERS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|tracker| tracker.progress.node_id == node_id)
+        .map(|tracker| (tracker.progress.id.clone(), node_id.to_string()))
+        .collect();
+    for (id, node_id) in keys.iter() {
+        cancel(id, node_id);
+        finish(id, node_id, TransferState::Failed);
+    }
+}
+
+pub fn list() -> Vec<Progress> {
+    let mut res: Vec<Progress> = TRACKERS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|tracker| tracker.progress.clone())
+        .collect();
+    res.sort_by_key(|progress| progress.started);
+    res
+}
+
+pub fn log(progress: &Progress) {
+    let direction = if progress.sending { "to" } else { "from" };
+    let rate = format!("{:.1} KB/s", progress.rate() / 1024.0);
+    match progress.state {
+        // offset 为继续传输或缓存中已有的字节数
+        TransferState::Running if progress.done == progress.offset => info!(
+            bytes = progress.size,
+            offset = progress.offset,
+            "Transfer {} {} {} started",
+            progress.id,
+            direction,
+            progress.node_id
+        ),
+        TransferState::Running => info!(
+            bytes = progress.done,
+            rate = %rate,
+            eta = progress.eta().map(|eta| eta.as_secs()).unwrap_or_default(),
+            "Transfer {} {} {}: {:.0}%",
+            progress.id,
+            direction,
+            progress.node_id,
+            progress.percent()
+        ),
+        TransferState::Finished => info!(
+            bytes = progress.done,
+            rate = %rate,
+            "Transfer {} {} {} finished", progress.id, direction, progress.node_id
+        ),
+        TransferState::Cancelled => info!(
+            bytes = progress.done,
+            "Transfer {} {} {} cancelled", progress.id, direction, progress.node_id
+        ),
+        TransferState::Failed => warn!(
+            bytes = progress.done,
+            "Transfer {} {} {} failed", progress.id, direction, progress.node_id
+        ),
+    }
+}
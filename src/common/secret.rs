@@ -0,0 +1,112 @@
+use super::super::datatype::{ClipboardEntry, SecretConfig, MIME_TEXT};
+use lazy_static::lazy_static;
+use regex_automata::meta::Regex;
+use std::sync::RwLock;
+
+// 常见的私钥, 访问密钥和令牌格式
+const BUILTIN_PATTERNS: [(&str, &str); 8] = [
+    (
+        "private key",
+        r"-----BEGIN [A-Z ]*PRIVATE KEY( BLOCK)?-----",
+    ),
+    ("AWS access key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+    (
+        "GitHub token",
+        r"\b(gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{22,})\b",
+    ),
+    ("Slack token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+    ("API key", r"\bsk-[A-Za-z0-9_-]{20,}"),
+    ("Google API key", r"\bAIza[0-9A-Za-z_-]{35}\b"),
+    (
+        "JSON web token",
+        r"\beyJ[A-Za-z0-9_-]{10,}\.[A-Za-z0-9_-]{10,}\.[A-Za-z0-9_-]{10,}",
+    ),
+    // 只匹配引号中的字面值, 变量, 函数调用和模板占位符不算
+    (
+        "password assignment",
+        r#"(?i)\b(password|passwd|secret|api[_-]?key|access[_-]?token)["']?\s*[:=]\s*["'][^"'\s${}<>]{8,}["']"#,
+    ),
+];
+
+struct Rule {
+    name: String,
+    regex: Regex,
+}
+
+lazy_static! {
+    static ref RULES: RwLock<Vec<Rule>> = RwLock::new(Vec::new());
+    static ref CLEAR_AFTER: RwLock<Option<u64>> = RwLock::new(None);
+}
+
+pub fn init(config: &SecretConfig) -> Result<(), String> {
+    let mut rules = Vec::new();
+    if config.builtin {
+        for (name, pattern) in BUILTIN_PATTERNS.iter() {
+            rules.push(Rule {
+                name: name.to_string(),
+                regex: Regex::new(pattern).unwrap(),
+            });
+        }
+    }
+    for pattern in config.deny.iter() {
+        let regex = Regex::new(pattern)
+            .map_err(|error| format!("Invalid pattern \"{}\": {}", pattern, error))?;
+        rules.push(Rule {
+            name: format!("pattern \"{}\"", pattern),
+            regex,
+        });
+    }
+    *RULES.write().unwrap() = rules;
+    *CLEAR_AFTER.write().unwrap() = config.clear_after;
+    Ok(())
+}
+
+// 返回判断为敏感内容的原因, 密码管理器的标记优先
+pub fn detect(entry: &ClipboardEntry) -> Option<String> {
+    if entry.sensitive {
+        return Some("marked as secret".to_string());
+    }
+    let text = entry.get(MIME_TEXT)?;
+    RULES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|rule| rule.regex.is_match(text))
+        .map(|rule| rule.name.clone())
+}
+
+// 敏感内容同步后在其他节点上清除的时间, 未设置时不发送
+pub fn clear_after() -> Option<u64> {
+    *CLEAR_AFTER.read().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect_text(text: &str) -> Option<String> {
+        let config = SecretConfig {
+            builtin: true,
+            deny: Vec::new(),
+            clear_after: None,
+        };
+        init(&config).unwrap();
+        detect(&ClipboardEntry::from_text("node-a", text.to_string()))
+    }
+
+    #[test]
+    fn password_needs_quoted_literal() {
+        let found = Some("password assignment".to_string());
+        assert_eq!(detect_text(r#"password = "hunter2hunter2""#), found);
+        assert_eq!(detect_text(r#"{"api_key": "Zx81kQ0pLm7"}"#), found);
+        assert_eq!(detect_text("secret: 'd9f8a7s6d5f4'"), found);
+
+        // 代码, 配置模板和 .env 中的引用
+        assert_eq!(detect_text("password = input(\"Password: \")"), None);
+        assert_eq!(detect_text("let password = read_password()?;"), None);
+        assert_eq!(detect_text("password: ${DB_PASSWORD}"), None);
+        assert_eq!(detect_text(r#"password: "{{ vault_password }}""#), None);
+        assert_eq!(detect_text("DB_PASSWORD=$DB_PASSWORD"), None);
+        assert_eq!(detect_text(r#"password = "short""#), None);
+    }
+}
@@ -0,0 +1,151 @@
+use super::super::datatype::{BandwidthConfig, RateLimit, RemoteClipboard};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time;
+
+// 令牌桶限速. 令牌数可以为负, 数据帧先发送再扣除令牌,
+// 分块传输的数据等待令牌补足后再发送或接收, 其他数据不等待
+
+struct Bucket {
+    rate: f64, // bytes per second
+    tokens: f64,
+    updated: time::Instant,
+}
+
+impl Bucket {
+    // 最多积累一秒的令牌
+    fn new(rate: usize) -> Bucket {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: time::Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+
+    // 令牌补足前需要等待的时间
+    fn delay(&mut self) -> time::Duration {
+        self.refill();
+        if self.tokens >= 0.0 {
+            return time::Duration::ZERO;
+        }
+        time::Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+type Limit = Mutex<Option<Bucket>>;
+
+lazy_static! {
+    // 所有节点的总速率
+    static ref UPLOAD: Limit = Mutex::new(None);
+    static ref DOWNLOAD: Limit = Mutex::new(None);
+    static ref CONFIG: Mutex<BandwidthConfig> = Mutex::new(BandwidthConfig::default());
+}
+
+// 最后一条规则生效
+fn total(rules: &[RateLimit]) -> Option<Bucket> {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.peer.is_none())
+        .map(|rule| Bucket::new(rule.rate))
+}
+
+fn for_peer(rules: &[RateLimit], node_id: &str, remote: &RemoteClipboard) -> Option<Bucket> {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.matches(node_id, remote))
+        .map(|rule| Bucket::new(rule.rate))
+}
+
+pub fn init(config: &BandwidthConfig) {
+    *UPLOAD.lock().unwrap() = total(&config.upload);
+    *DOWNLOAD.lock().unwrap() = total(&config.download);
+    *CONFIG.lock().unwrap() = config.clone();
+}
+
+fn consume(limits: [&Limit; 2], bytes: usize) {
+    for limit in limits {
+        if let Some(bucket) = limit.lock().unwrap().as_mut() {
+            bucket.consume(bytes);
+        }
+    }
+}
+
+async fn wait(limits: [&Limit; 2]) {
+    loop {
+        let delay = limits
+            .iter()
+            .filter_map(|limit| limit.lock().unwrap().as_mut().map(Bucket::delay))
+            .max()
+            .unwrap_or_default();
+        if delay.is_zero() {
+            return;
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// 一个连接的限速, 同时受总速率和该节点速率的限制
+pub struct Limiter {
+    upload: Limit,
+    download: Limit,
+}
+
+impl Limiter {
+    pub fn new(node_id: &str, remote: &RemoteClipboard) -> Limiter {
+        let config = CONFIG.lock().unwrap();
+        Limiter {
+            upload: Mutex::new(for_peer(&config.upload, node_id, remote)),
+            download: Mutex::new(for_peer(&config.download, node_id, remote)),
+        }
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        consume([&UPLOAD, &self.upload], bytes);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        consume([&DOWNLOAD, &self.download], bytes);
+    }
+
+    pub async fn wait_upload(&self) {
+        wait([&UPLOAD, &self.upload]).await;
+    }
+
+    pub async fn wait_download(&self) {
+        wait([&DOWNLOAD, &self.download]).await;
+    }
+
+    // 本地对该节点的下载限速, 发给对方以便从发送端限速
+    pub fn download_rate(&self) -> Option<usize> {
+        [&DOWNLOAD, &self.download]
+            .iter()
+            .filter_map(|limit| limit.lock().unwrap().as_ref().map(|bucket| bucket.rate))
+            .min_by(f64::total_cmp)
+            .map(|rate| rate as usize)
+    }
+
+    // 对方的下载限速低于本地设置时使用对方的限速
+    pub fn restrict_upload(&self, rate: usize) {
+        let mut upload = self.upload.lock().unwrap();
+        if upload
+            .as_ref()
+            .is_none_or(|bucket| bucket.rate > rate as f64)
+        {
+            *upload = Some(Bucket::new(rate));
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use super::super::datatype::{PeerPin, TlsConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{info, warn};
+
+const CERT_FILE: &str = "tls_cert.der";
+const KEY_FILE: &str = "tls_key.der";
+const KNOWN_PEERS_FILE: &str = "known_peers";
+const SERVER_NAME: &str = "uniclipboard";
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type Stream = Box<dyn AsyncStream>;
+
+impl FromStr for PeerPin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (node_id, fingerprint) = match s.split_once('=') {
+            Some(res) => res,
+            None => {
+                return Err(format!(
+                    "Invalid pin \"{}\", expected <node id>=<sha256>",
+                    s
+                ))
+            }
+        };
+        let fingerprint = fingerprint.replace(':', "").to_lowercase();
+        if node_id.is_empty() || fingerprint.len() != 64 || hex::decode(&fingerprint).is_err() {
+            return Err(format!(
+                "Invalid pin \"{}\", expected <node id>=<sha256>",
+                s
+            ));
+        }
+        Ok(PeerPin {
+            node_id: node_id.to_string(),
+            fingerprint,
+        })
+    }
+}
+
+pub fn fingerprint(cert: &[u8]) -> String {
+    hex::encode(Sha256::digest(cert))
+}
+
+// 证书都是自签名的, 握手时只检查签名, 证书在收到对方节点 ID 后按指纹校验
+#[derive(Debug)]
+struct PinVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PinVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// 读取本节点的证书和私钥, 不存在时生成自签名证书并保存
+fn load_cert(data_dir: &Path) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let cert_path = data_dir.join(CERT_FILE);
+    let key_path = data_dir.join(KEY_FILE);
+    if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+        return Ok((CertificateDer::from(cert), key));
+    }
+
+    let generated = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+        .map_err(io::Error::other)?;
+    let cert = generated.cert.der().clone();
+    let key = generated.key_pair.serialize_der();
+    fs::create_dir_all(data_dir)?;
+    fs::write(&cert_path, &cert)?;
+    write_private(&key_path, &key)?;
+    info!("Generated TLS certificate {}", cert_path.display());
+    Ok((cert, PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key))))
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::write(path, data)
+}
+
+// 已知节点的证书指纹, 每行格式: <node id> <sha256>
+fn load_known_peers(path: &Path) -> HashMap<String, String> {
+    let mut known = HashMap::new();
+    if let Ok(data) = fs::read_to_string(path) {
+        for line in data.lines() {
+            if let Some((node_id, fingerprint)) = line.trim().split_once(' ') {
+                known.insert(node_id.to_string(), fingerprint.trim().to_string());
+            }
+        }
+    }
+    known
+}
+
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    fingerprint: String,
+    known_peers: PathBuf,
+    pins: Mutex<HashMap<String, String>>,
+}
+
+impl Tls {
+    pub fn load(config: &TlsConfig) -> io::Result<Tls> {
+        let (cert, key) = load_cert(&config.data_dir)?;
+        let fingerprint = fingerprint(&cert);
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let verifier = Arc::new(PinVerifier(provider.clone()));
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_client_auth_cert(vec![cert.clone()], key.clone_key())
+            .map_err(io::Error::other)?;
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![cert], key)
+            .map_err(io::Error::other)?;
+
+        // 命令行指定的指纹优先于记录的指纹
+        let known_peers = config.data_dir.join(KNOWN_PEERS_FILE);
+        let mut pins = load_known_peers(&known_peers);
+        for pin in config.pins.iter() {
+            pins.insert(pin.node_id.clone(), pin.fingerprint.clone());
+        }
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+            fingerprint,
+            known_peers,
+            pins: Mutex::new(pins),
+        })
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    // 返回加密后的连接和对方证书的指纹
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<(Stream, String)> {
+        let name = ServerName::try_from(SERVER_NAME).unwrap();
+        let stream = self.connector.connect(name, stream).await?;
+        let peer = match stream.get_ref().1.peer_certificates() {
+            Some([cert, ..]) => fingerprint(cert),
+            _ => return Err(io::Error::other("No peer certificate")),
+        };
+        Ok((Box::new(stream), peer))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<(Stream, String)> {
+        let stream = self.acceptor.accept(stream).await?;
+        let peer = match stream.get_ref().1.peer_certificates() {
+            Some([cert, ..]) => fingerprint(cert),
+            _ => return Err(io::Error::other("No peer certificate")),
+        };
+        Ok((Box::new(stream), peer))
+    }
+
+    // 首次连接时记录节点的证书指纹, 之后证书不一致时拒绝连接
+    pub fn verify(&self, node_id: &str, fingerprint: &str) -> bool {
+        let mut pins = self.pins.lock().unwrap();
+        match pins.get(node_id) {
+            Some(pinned) => pinned == fingerprint,
+            None => {
+                pins.insert(node_id.to_string(), fingerprint.to_string());
+                let res = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.known_peers)
+                    .and_then(|mut file| writeln!(file, "{} {}", node_id, fingerprint));
+                if let Err(error) = res {
+                    warn!("Unable to save {}: {}", self.known_peers.display(), error);
+                }
+                info!("Pinned certificate {} for {}", fingerprint, node_id);
+                true
+            }
+        }
+    }
+}
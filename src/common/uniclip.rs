@@ -1,406 +1,2246 @@
-use crate::datatype::payload_type::PEER;
-
 use super::super::datatype::{
-    payload_type, LocalClipboard, RemoteClipboard, UniclipPayload, UNICLIP_DATA_LIMIT,
+    payload_type, ClearConfig, ClipboardEntry, DiscoveryConfig, EntryInfo, HeadlessConfig,
+    HotkeyConfig, LocalClipboard, Member, RemoteClipboard, SyncPolicy, UniclipPayload,
+    UniclipRoute, MIME_TEXT, UNICLIP_BIG_LIMIT, UNICLIP_FRAME_LIMIT, UNICLIP_MAX_HOPS,
 };
 
-use super::{clipboard, hotkey, message, packer};
-use hotkey::{Hotkey, HotkeyManager};
+use super::membership::Membership;
+use super::progress::{self, TransferState};
+use super::throttle::{self, Limiter};
+use super::tls::{self, Stream};
+use super::{chunks, clipboard, discovery, hotkey, logging, packer, partial, secret};
+use hotkey::HotkeyManager;
+use lazy_static::lazy_static;
 use rand::prelude::*;
 use serde_encrypt::shared_key::SharedKey;
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::mem::MaybeUninit;
-use std::net::{TcpListener, TcpStream};
-use std::sync::Mutex;
-use std::{thread, time};
-
-struct MQItem {
-    index: usize,
+use socket2::{Domain, Socket, Type};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::time;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast as channel, mpsc, oneshot, watch, Notify};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+const HEARTBEAT_INTERVAL: u64 = 10;
+const HEARTBEAT_TIMEOUT: u64 = 5;
+const HANDSHAKE_TIMEOUT: u64 = 10;
+const TRANSFER_TIMEOUT: u64 = 10;
+// 等待用户确认的时间, 发送方等待确认时需要加上这段时间
+const CONFIRM_TIMEOUT: u64 = 60;
+const RECONNECT_BACKOFF: u64 = 60;
+const SEEN_LIMIT: usize = 1024;
+// 每个连接的发送队列长度, 队列满时发送方等待
+const SEND_QUEUE_LIMIT: usize = 64;
+// 分块传输的数据单独排队, 只在没有其他数据时发送
+const BULK_QUEUE_LIMIT: usize = 4;
+const HISTORY_LIMIT: usize = 50;
+const SHUTDOWN_TIMEOUT: u64 = 2;
+const INCOMING_LIMIT: usize = 16;
+const RECEIVE_DIR_NAME: &str = "received";
+const PARTIAL_DIR_NAME: &str = "partial";
+const CHUNKS_DIR_NAME: &str = "chunks";
+// 中断的发送保留的时间, 以及对方重连后等待交换分组信息的时间
+const RESUME_TIMEOUT: u64 = 30 * 60;
+const RESUME_DELAY: u64 = 1;
+// 保留的延迟发送的条目和收到的条目信息数量
+const LAZY_LIMIT: usize = 4;
+const OFFER_LIMIT: usize = 16;
+const OFFER_PREVIEW_LIMIT: usize = 64;
+// 请求获取后等待内容的时间, 大的条目仍在传输时继续等待
+const FETCH_TIMEOUT: u64 = 10 * 60;
+
+static PORT: AtomicU16 = AtomicU16::new(0);
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+static RELAY: AtomicBool = AtomicBool::new(false);
+// 判断为敏感内容而没有发送的次数, 在 status 中显示
+static BLOCKED_SECRETS: AtomicUsize = AtomicUsize::new(0);
+// 内部的进度回调只注册一次, init 再次调用时不重复注册
+static CALLBACKS: Once = Once::new();
+
+lazy_static! {
+    static ref NODE_ID: RwLock<String> = RwLock::new(String::new());
+    static ref MEMBERS: Mutex<Membership> = Mutex::new(Membership::new("", 0));
+    static ref CONNECTING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref FAILED: Mutex<HashMap<String, time::Instant>> = Mutex::new(HashMap::new());
+    // 命令行指定的节点及其节点 ID, 断开后定期重连
+    static ref STATIC_PEERS: Mutex<Vec<(RemoteClipboard, Option<String>)>> =
+        Mutex::new(Vec::new());
+    // 手动断开的节点, 再次手动连接前不自动重连
+    static ref BLOCKED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref SEEN: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+    static ref HISTORY: Mutex<VecDeque<HistoryEntry>> = Mutex::new(VecDeque::new());
+    static ref LISTEN: RwLock<Vec<SocketAddr>> = RwLock::new(Vec::new());
+    static ref SHUTDOWN: Notify = Notify::new();
+    static ref LAST_BLOCKED: Mutex<Option<(time::SystemTime, String)>> = Mutex::new(None);
+    // 等待用户确认的更新, 以消息 ID 为键
+    static ref CONFIRMS: Mutex<HashMap<String, (Confirmation, oneshot::Sender<bool>)>> =
+        Mutex::new(HashMap::new());
+    // 收到的更新, 供 recv 命令等待
+    static ref INCOMING: channel::Sender<Arc<ClipboardEntry>> = channel::channel(INCOMING_LIMIT).0;
+    static ref RECEIVE_DIR: RwLock<PathBuf> = RwLock::new(PathBuf::new());
+    static ref KEY: RwLock<SharedKey> = RwLock::new(SharedKey::new([0u8; 32]));
+    static ref TLS: RwLock<Option<Arc<tls::Tls>>> = RwLock::new(None);
+    static ref RUNTIME: RwLock<Option<Handle>> = RwLock::new(None);
+    static ref HANDLERS: Mutex<HashMap<String, Arc<UniclipPeerHandler>>> =
+        Mutex::new(HashMap::new());
+    static ref POLICY: RwLock<SyncPolicy> = RwLock::new(SyncPolicy::default());
+    // 正在发送的分块传输, 因连接断开而中断时保留到对方重连
+    static ref OUTGOING: Mutex<HashMap<String, Outgoing>> = Mutex::new(HashMap::new());
+    static ref CLEAR: RwLock<ClearConfig> = RwLock::new(ClearConfig::default());
+    static ref LAZY_SIZE: RwLock<Option<usize>> = RwLock::new(None);
+    // 只发送了条目信息的条目, 对方获取时发送内容
+    static ref LAZY: Mutex<VecDeque<(String, Arc<ClipboardEntry>)>> = Mutex::new(VecDeque::new());
+    static ref OFFERS: Mutex<VecDeque<Offer>> = Mutex::new(VecDeque::new());
+    // 正在获取的条目, 以 hash 为键, 收到后通知等待的任务
+    static ref FETCHING: Mutex<HashMap<String, Fetching>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn init() {
+    HANDLERS.lock().unwrap().clear();
+    CONNECTING.lock().unwrap().clear();
+    FAILED.lock().unwrap().clear();
+    BLOCKED.lock().unwrap().clear();
+    SEEN.lock().unwrap().clear();
+    HISTORY.lock().unwrap().clear();
+    OUTGOING.lock().unwrap().clear();
+    LAZY.lock().unwrap().clear();
+    OFFERS.lock().unwrap().clear();
+    FETCHING.lock().unwrap().clear();
+    progress::reset();
+    CALLBACKS.call_once(|| {
+        progress::on_progress(progress::log);
+        progress::on_progress(forget_outgoing);
+    });
+}
+
+pub fn node_id() -> String {
+    NODE_ID.read().unwrap().clone()
+}
+
+fn is_known(node_id: &str) -> bool {
+    HANDLERS.lock().unwrap().contains_key(node_id)
+}
+
+fn is_blocked(node_id: &str) -> bool {
+    BLOCKED.lock().unwrap().contains(node_id)
+}
+
+fn handlers() -> Vec<Arc<UniclipPeerHandler>> {
+    HANDLERS.lock().unwrap().values().cloned().collect()
+}
+
+// 敏感内容不保存到历史记录
+fn record(source: &str, entry: &Arc<ClipboardEntry>) {
+    if entry.sensitive {
+        return;
+    }
+    let mut history = HISTORY.lock().unwrap();
+    if history.len() >= HISTORY_LIMIT {
+        history.pop_front();
+    }
+    history.push_back(HistoryEntry {
+        time: time::SystemTime::now(),
+        source: source.to_string(),
+        entry: entry.clone(),
+    });
+}
+
+// 收到的文件保存到数据目录, 剪贴板中写入文件路径
+fn received_path(name: &str) -> PathBuf {
+    // 只保留文件名, 防止写到目录之外
+    let name = match Path::new(name).file_name() {
+        Some(name) => name.to_owned(),
+        None => "clipboard.bin".into(),
+    };
+    RECEIVE_DIR.read().unwrap().join(name)
+}
+
+fn save_file(name: &str, data: &[u8]) -> Option<PathBuf> {
+    let path = received_path(name);
+    let dir = path.parent().unwrap();
+    let res = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, data));
+    match res {
+        Ok(_) => Some(path),
+        Err(error) => {
+            error!("{}: {}", path.display(), error);
+            None
+        }
+    }
+}
+
+// 只在需要时复制内容, 收到的文件直接写入数据目录, 写入后记录读回的内容供清空时比较
+fn apply(entry: Arc<ClipboardEntry>, hash: &str) {
+    if clipboard::is_headless() {
+        clipboard::store(entry.as_ref().clone());
+    } else if let Some(name) = &entry.name {
+        if let Some(path) = save_file(name, entry.bytes()) {
+            info!("Received file {}", path.display());
+            clipboard::set(path.display().to_string());
+        }
+    } else if entry.is_image() {
+        clipboard::set_image(entry.bytes().to_vec());
+    } else {
+        clipboard::set_formats(entry.formats.clone());
+    }
+    clipboard::mark(hash, &entry.origin);
+}
+
+// 读取一个加密的数据帧, 连接关闭或出错时返回 None
+async fn read_buffer<R: AsyncRead + Unpin>(reader: &mut R) -> Option<Vec<u8>> {
+    let size = reader.read_u32().await.ok()? as usize;
+    if size > UNICLIP_FRAME_LIMIT {
+        error!("Data frame too large ({} bytes)", size);
+        return None;
+    }
+
+    let mut buffer = vec![0u8; size];
+    match reader.read_exact(&mut buffer).await {
+        Ok(_) => Some(buffer),
+        Err(error) => {
+            error!("{}", error);
+            None
+        }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, key: &SharedKey) -> UniclipPayload {
+    match read_buffer(reader).await {
+        Some(buffer) => packer::unpack(buffer, key),
+        None => UniclipPayload::ShutDown,
+    }
+}
+
+async fn write_buffer<W: AsyncWrite + Unpin>(writer: &mut W, buf: &[u8]) -> io::Result<()> {
+    // 每个数据帧前加 4 字节长度
+    writer.write_u32(buf.len() as u32).await?;
+    writer.write_all(buf).await?;
+    writer.flush().await
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    key: &SharedKey,
     data: UniclipPayload,
+) -> io::Result<()> {
+    write_buffer(writer, &packer::pack(data, key)).await
 }
 
-static mut PORT: u16 = 0;
-static mut MQ: MaybeUninit<Mutex<HashMap<String, Vec<MQItem>>>> = MaybeUninit::uninit();
-static mut PEERS: MaybeUninit<Mutex<Vec<RemoteClipboard>>> = MaybeUninit::uninit();
-static mut HANDLERS: MaybeUninit<Mutex<Vec<UniclipPeerHandler>>> = MaybeUninit::uninit();
+// 大的条目计算 hash 耗时较长, 在阻塞线程中进行
+async fn hash_entry(entry: &Arc<ClipboardEntry>) -> String {
+    let entry = entry.clone();
+    tokio::task::spawn_blocking(move || packer::hash(entry.as_ref()))
+        .await
+        .unwrap_or_default()
+}
 
-pub fn init() {
-    unsafe {
-        let peers = PEERS.as_mut_ptr();
-        peers.write(Mutex::new(Vec::new()));
-        let handlers = HANDLERS.as_mut_ptr();
-        handlers.write(Mutex::new(Vec::new()));
-
-        let mq = MQ.as_mut_ptr();
-        let mut mq_data = HashMap::new();
-        mq_data.insert(payload_type::PORT_RES.clone(), Vec::new());
-        mq_data.insert(payload_type::ECHO_RES.clone(), Vec::new());
-        mq_data.insert(payload_type::PEER_LIST.clone(), Vec::new());
-        mq_data.insert(payload_type::UPDATE.clone(), Vec::new());
-        mq_data.insert(payload_type::UPDATE_RES.clone(), Vec::new());
-        mq_data.insert(payload_type::UPDATE_BIG_ACK.clone(), Vec::new());
-        mq_data.insert(payload_type::UPDATE_BIG_FINISH.clone(), Vec::new());
-        mq_data.insert(payload_type::QUIT_RES.clone(), Vec::new());
-        mq.write(Mutex::new(mq_data));
-    }
-}
-
-fn handle(index: usize) {
-    thread::spawn(move || unsafe {
-        let handlers = HANDLERS.as_mut_ptr().read();
-
-        let need_big = false;
-        let big_size: usize = 0;
-        loop {
-            let data: UniclipPayload;
-            if !need_big {
-                let handler = &handlers.lock().unwrap()[index];
-                data = handler.recv();
+// 校验并应用收到的条目, 然后转发给其他节点
+async fn receive(
+    handler: &Arc<UniclipPeerHandler>,
+    route: UniclipRoute,
+    hash: String,
+    entry: ClipboardEntry,
+) {
+    let entry = Arc::new(entry);
+    if hash != hash_entry(&entry).await {
+        let res = UniclipPayload::Error("Update hash error".to_string());
+        handler.send(res).await;
+        return;
+    }
+    // 只使用允许接收的格式, 转发时使用完整的条目, 全部接收时共用同一份内容
+    let accepted = handler.accepted(&entry.info());
+    let content = match entry
+        .formats
+        .iter()
+        .all(|(mime, _)| accepted.contains(mime))
+    {
+        true => entry.clone(),
+        false => {
+            let mut content = entry.as_ref().clone();
+            content.formats.retain(|(mime, _)| accepted.contains(mime));
+            Arc::new(content)
+        }
+    };
+    let size = content.size();
+    let (applied, applied_hash) = (content.clone(), hash.clone());
+    let _ = tokio::task::spawn_blocking(move || apply(applied, &applied_hash)).await;
+    debug!(
+        node_id = %handler.node_id,
+        origin = %content.origin,
+        mime = %content.mime(),
+        bytes = size,
+        "Received update"
+    );
+    record(&handler.node_id, &content);
+    // 发送方要求的时间和本地设置的时间取较短的一个
+    let after = CLEAR.read().unwrap().after;
+    let seconds = match (content.clear_after, after) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    if let Some(seconds) = seconds {
+        tokio::spawn(clear_later(hash.clone(), seconds));
+    }
+    let _ = INCOMING.send(content);
+    handler.send(UniclipPayload::UpdateRes(size)).await;
+    // 请求获取的条目不再转发, 保留完整的条目供其他经本节点获取的节点使用
+    let fetching = FETCHING.lock().unwrap().remove(&hash);
+    if let Some(fetching) = fetching {
+        keep(hash, entry);
+        for waiter in fetching.waiters {
+            let _ = waiter.send(());
+        }
+        return;
+    }
+    // 转发大的条目需要较长时间, 不阻塞读任务
+    tokio::spawn(relay(route, hash, entry, handler.node_id.clone()));
+}
+
+// 剪贴板仍是指定的条目时清空, 返回是否清空
+async fn clear_if(hash: &str) -> bool {
+    let (hash, local_id) = (hash.to_string(), node_id());
+    let clear = move || {
+        let holds = clipboard::holds(&hash, &local_id);
+        if holds {
+            clipboard::clear();
+        }
+        holds
+    };
+    tokio::task::spawn_blocking(clear).await.unwrap_or(false)
+}
+
+// 通知其他节点清空同一条内容, 不发回给来源节点
+fn propagate_clear(hash: &str, source: Option<&str>) {
+    for handler in handlers() {
+        if handler.can_send() && Some(handler.node_id.as_str()) != source {
+            let handler = handler.clone();
+            let data = UniclipPayload::Clear(hash.to_string());
+            tokio::spawn(async move { handler.send(data).await });
+        }
+    }
+}
+
+// 到时间后剪贴板仍是收到的内容时清空, 其他节点使用同一个条目 hash 比较
+async fn clear_later(hash: String, seconds: u64) {
+    tokio::time::sleep(time::Duration::from_secs(seconds)).await;
+    if !clear_if(&hash).await {
+        return;
+    }
+    info!("Cleared received content from the clipboard");
+    if CLEAR.read().unwrap().propagate {
+        propagate_clear(&hash, None);
+    }
+}
+
+async fn dispatch(handler: &Arc<UniclipPeerHandler>, data: UniclipPayload) -> bool {
+    match data {
+        UniclipPayload::Echo(data) => {
+            handler
+                .send(UniclipPayload::EchoRes(data.wrapping_add(1)))
+                .await;
+        }
+        UniclipPayload::EchoRes(..) => {
+            handler.respond(&payload_type::ECHO_RES, data);
+        }
+        UniclipPayload::Gossip(members) => {
+            merge_members(handler, members).await;
+        }
+        UniclipPayload::Groups(groups) => {
+            *handler.groups.lock().unwrap() = groups;
+        }
+        UniclipPayload::Throttle(rate) if rate > 0 => {
+            handler.limiter.restrict_upload(rate);
+        }
+        UniclipPayload::Update(route, hash, entry) => {
+            // 经其他路径已经收到过的消息
+            if !mark_seen(&route.id) {
+                return true;
+            }
+            let info = entry.info();
+            if let Err(error) = handler.admit(&info) {
+                handler.send(UniclipPayload::Error(error)).await;
+                return true;
+            }
+            // 请求获取的条目已经由用户确认
+            if !is_fetching(&hash) && handler.needs_confirm(&info) {
+                // 等待确认时不阻塞读任务
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if confirm(&route.id, &handler, info).await {
+                        receive(&handler, route, hash, entry).await;
+                    }
+                });
             } else {
-                let handler = &handlers.lock().unwrap()[index];
-                data = handler.recv_big(big_size);
+                receive(handler, route, hash, entry).await;
             }
-            match data {
-                UniclipPayload::Echo(data) => {
-                    let handler = &handlers.lock().unwrap()[index];
-                    handler.send(UniclipPayload::EchoRes(data + 1));
-                }
-                UniclipPayload::EchoRes(..) => {
-                    insert(index, &payload_type::ECHO_RES, data);
-                }
-                UniclipPayload::Peer(rand_a) => {
-                    let peers = PEERS.as_ptr();
-                    let res =
-                        UniclipPayload::PeerList(rand_a + 1, (*peers).lock().unwrap().clone());
-                    let handler = &handlers.lock().unwrap()[index];
-                    handler.send(res);
-                }
-                UniclipPayload::PeerList(..) => {
-                    insert(index, &payload_type::PEER_LIST, data);
-                }
-                UniclipPayload::Port(rand_a) => {
-                    let port = PORT;
-                    let res = UniclipPayload::PortRes(rand_a + 1, port);
-                    let handler = &handlers.lock().unwrap()[index];
-                    handler.send(res);
-                }
-                UniclipPayload::PortRes(..) => {
-                    insert(index, &payload_type::PORT_RES, data);
+        }
+        UniclipPayload::UpdateRes(..) => {
+            handler.respond(&payload_type::UPDATE_RES, data);
+        }
+        UniclipPayload::UpdateBig(route, hash, info, hashes) => {
+            let id = route.id.clone();
+            if !partial::valid(&hash, &info.id) {
+                let error = format!("Invalid update {}", id);
+                handler.send(UniclipPayload::Error(error)).await;
+                handler
+                    .send(UniclipPayload::UpdateBigAck(id, false, Vec::new()))
+                    .await;
+                return true;
+            }
+            // 已经收到过的消息只有未完成时才继续接收
+            let resuming = partial::exists(&hash, &info.id);
+            if !mark_seen(&id) && !resuming {
+                handler
+                    .send(UniclipPayload::UpdateBigAck(id, false, Vec::new()))
+                    .await;
+                return true;
+            }
+            // 大小由接收策略限制
+            let chunks = info.chunks();
+            let res = match handler.admit(&info) {
+                Ok(_) if hashes.len() != chunks.len() => Err("Invalid chunk list".to_string()),
+                res => res,
+            };
+            let open = {
+                let (hash, entry_id, sensitive) = (hash.clone(), info.id.clone(), info.sensitive);
+                move || partial::open(&hash, &entry_id, chunks, sensitive)
+            };
+            let res = match res {
+                Ok(_) => match tokio::task::spawn_blocking(open).await {
+                    Ok(res) => res,
+                    Err(error) => Err(error.to_string()),
+                },
+                Err(error) => Err(error),
+            };
+            let partial = match res {
+                Ok(partial) => partial,
+                Err(error) => {
+                    handler.send(UniclipPayload::Error(error)).await;
+                    handler
+                        .send(UniclipPayload::UpdateBigAck(id, false, Vec::new()))
+                        .await;
+                    return true;
                 }
-                UniclipPayload::Update(hash, data) => {
-                    let data_hash = packer::hash(&data);
-                    if hash == data_hash {
-                        let res = UniclipPayload::UpdateRes(data.len());
-                        clipboard::set(data);
-                        let handler = &handlers.lock().unwrap()[index];
-                        handler.send(res);
-                    } else {
-                        let res = UniclipPayload::Error("Update text hash error".to_string());
-                        let handler = &handlers.lock().unwrap()[index];
-                        handler.send(res);
+            };
+            let transfer = Transfer {
+                route,
+                hash,
+                info: info.clone(),
+                partial,
+                hashes,
+                requested: HashSet::new(),
+                pin: None,
+            };
+            // 继续中断的传输和请求获取的条目不需要再确认
+            if !resuming && !is_fetching(&transfer.hash) && handler.needs_confirm(&info) {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let accepted = confirm(&id, &handler, info).await;
+                    handler.start_transfer(id, transfer, accepted).await;
+                });
+            } else {
+                handler.start_transfer(id, transfer, true).await;
+            }
+        }
+        UniclipPayload::UpdateBigAck(ref id, ..) => {
+            handler.respond(&transfer_key(id), data);
+        }
+        UniclipPayload::UpdateBigData(id, index, chunk) => {
+            // 写入文件时不持有锁, 期间被取消的传输写入后丢弃
+            let transfer = handler.transfers.lock().unwrap().remove(&id);
+            let mut transfer = match transfer {
+                Some(transfer) => transfer,
+                None => return true,
+            };
+            let res = tokio::task::spawn_blocking(move || {
+                let res = transfer.write(index, &chunk);
+                (transfer, res)
+            })
+            .await;
+            let (transfer, res) = match res {
+                Ok(res) => res,
+                Err(_) => return true,
+            };
+            match res {
+                Ok(size) if progress::running(&id, &handler.node_id) => {
+                    progress::advance(&id, &handler.node_id, size);
+                    if let Some(fetching) = FETCHING.lock().unwrap().get_mut(&transfer.hash) {
+                        fetching.active = Some(time::Instant::now());
                     }
+                    handler.transfers.lock().unwrap().insert(id, transfer);
                 }
-                UniclipPayload::UpdateRes(..) => {
-                    insert(index, &payload_type::UPDATE_RES, data);
+                Ok(_) => transfer.partial.discard(),
+                Err(error) => {
+                    error!("{}: {}", handler.remote, error);
+                    transfer.partial.discard();
+                    progress::finish(&id, &handler.node_id, TransferState::Failed);
                 }
-                _ => {
-                    message::error("Invalid uniclip data.".to_string());
-                    std::process::exit(-1);
+            }
+        }
+        UniclipPayload::UpdateBigFinish(id) => {
+            let transfer = handler.transfers.lock().unwrap().remove(&id);
+            let transfer = match transfer {
+                Some(transfer) => transfer,
+                None => return true,
+            };
+            progress::finish(&id, &handler.node_id, TransferState::Finished);
+            // receive 校验整个条目的 hash
+            let (route, hash, info, partial) = (
+                transfer.route,
+                transfer.hash,
+                transfer.info,
+                transfer.partial,
+            );
+            let res = tokio::task::spawn_blocking(move || {
+                partial
+                    .finish(&info)
+                    .map(|contents| ClipboardEntry::from_info(info, contents))
+            })
+            .await;
+            match res {
+                Ok(Ok(entry)) => receive(handler, route, hash, entry).await,
+                Ok(Err(error)) => handler.send(UniclipPayload::Error(error)).await,
+                Err(_) => (),
+            }
+        }
+        UniclipPayload::UpdateBigCancel(id) => {
+            // 对方取消时丢弃已收到的数据, 或者停止发送
+            let transfer = handler.transfers.lock().unwrap().remove(&id);
+            if let Some(transfer) = transfer {
+                transfer.partial.discard();
+            }
+            if progress::cancel(&id, &handler.node_id).is_some() {
+                progress::finish(&id, &handler.node_id, TransferState::Cancelled);
+            }
+        }
+        UniclipPayload::Pull => {
+            if !handler.can_send() {
+                let res =
+                    UniclipPayload::Error(format!("Sync to {} is not allowed", handler.remote));
+                handler.send(res).await;
+                return true;
+            }
+            if let Some(offer) = clipboard_offer() {
+                handler.send(offer).await;
+                return true;
+            }
+            let local_id = node_id();
+            let entry = match tokio::task::spawn_blocking(move || clipboard::get(&local_id)).await {
+                Ok(entry) => entry,
+                Err(_) => return true,
+            };
+            let entry = match guard(entry) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    handler.send(UniclipPayload::Error(error)).await;
+                    return true;
                 }
+            };
+            // 大的条目需要等待对方确认, 不能阻塞读任务
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let entry = Arc::new(entry);
+                let hash = hash_entry(&entry).await;
+                deliver(&handler, new_route(), hash, &entry).await;
+            });
+        }
+        UniclipPayload::Clear(hash) => {
+            if !handler.can_recv() || !clear_if(&hash).await {
+                return true;
+            }
+            info!(node_id = %handler.node_id, "Cleared the clipboard on request");
+            // 清空后不再匹配, 转发不会形成循环
+            if RELAY.load(Ordering::SeqCst) {
+                propagate_clear(&hash, Some(&handler.node_id));
             }
         }
-    });
+        UniclipPayload::Offer(route, hash, info, preview) => {
+            if !mark_seen(&route.id) {
+                return true;
+            }
+            if let Err(error) = handler.admit(&info) {
+                handler.send(UniclipPayload::Error(error)).await;
+                return true;
+            }
+            info!(
+                node_id = %handler.node_id,
+                bytes = info.size(),
+                "Update {} is offered, it is fetched when pasted",
+                route.id
+            );
+            let offer = Offer {
+                route,
+                hash,
+                info,
+                preview,
+                source: handler.node_id.clone(),
+            };
+            remember(offer.clone());
+            offer_clipboard(handler, &offer).await;
+            tokio::spawn(relay_offer(offer));
+        }
+        UniclipPayload::Fetch(hash) => {
+            let entry = lazy_entry(&hash);
+            // 转发的节点没有内容时先从上游获取
+            let offer = match entry {
+                Some(_) => None,
+                None => find_offer(|offer| offer.hash == hash),
+            };
+            let available = handler.can_send() && (entry.is_some() || offer.is_some());
+            handler
+                .send(UniclipPayload::FetchRes(hash.clone(), available))
+                .await;
+            if !available {
+                return true;
+            }
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let entry = match (entry, offer) {
+                    (Some(entry), _) => entry,
+                    (None, Some(offer)) => match fetch_offer(&offer).await {
+                        Ok(entry) => entry,
+                        Err(error) => {
+                            handler.send(UniclipPayload::Error(error)).await;
+                            return;
+                        }
+                    },
+                    (None, None) => return,
+                };
+                deliver(&handler, new_route(), hash, &entry).await;
+            });
+        }
+        UniclipPayload::FetchRes(ref hash, _) => {
+            handler.respond(&fetch_key(hash), data);
+        }
+        UniclipPayload::Error(error) => {
+            warn!("{}: {}", handler.remote, error);
+        }
+        _ => {
+            error!("Invalid uniclip data.");
+            return false;
+        }
+    }
+    true
+}
+
+// 同一对节点之间只保留一条连接: 由 ID 较小的节点发起的连接
+fn prefer(new: &UniclipPeerHandler, existing: &UniclipPeerHandler) -> bool {
+    if new.outgoing == existing.outgoing {
+        // 同方向的新连接 (例如对方换了地址重连) 替换旧连接
+        return true;
+    }
+    new.outgoing == (node_id() < new.node_id)
+}
+
+async fn add_handler(handler: UniclipPeerHandler, conn: Connection) -> bool {
+    let node_id = handler.node_id.clone();
+    let remote = handler.remote.clone();
+
+    let handler = Arc::new(handler);
+    {
+        let mut handlers = HANDLERS.lock().unwrap();
+        if let Some(existing) = handlers.get(&node_id) {
+            if !prefer(&handler, existing) {
+                return false;
+            }
+            existing.close();
+        }
+        handlers.insert(node_id.clone(), handler.clone());
+    }
+    tokio::spawn(run(handler.clone(), conn));
+    tokio::spawn(resume(handler.clone()));
+
+    let groups = POLICY.read().unwrap().groups.clone();
+    handler.send(UniclipPayload::Groups(groups)).await;
+    if let Some(rate) = handler.limiter.download_rate() {
+        handler.send(UniclipPayload::Throttle(rate)).await;
+    }
+
+    // 新连接获取完整的成员列表, 其他节点获取变更
+    let (update, snapshot) = {
+        let mut members = MEMBERS.lock().unwrap();
+        (members.join(&node_id, &remote), members.snapshot())
+    };
+    handler.send(UniclipPayload::Gossip(snapshot)).await;
+    if let Some(update) = update {
+        gossip(vec![update], Some(&node_id)).await;
+    }
+    true
 }
 
-fn insert(index: usize, mtype: &String, data: UniclipPayload) {
-    unsafe {
-        let mq = MQ.as_mut_ptr();
-        let mut mq = (*mq).lock().unwrap();
-        mq.get_mut(mtype).unwrap().push(MQItem { index, data });
+// 分块传输的数据帧, 返回消息 ID 和数据长度, 结束帧没有长度
+fn transfer_frame(data: &UniclipPayload) -> Option<(String, Option<usize>)> {
+    match data {
+        UniclipPayload::UpdateBigData(id, _, chunk) => Some((id.clone(), Some(chunk.len()))),
+        UniclipPayload::UpdateBigFinish(id) => Some((id.clone(), None)),
+        _ => None,
     }
 }
 
-fn acquire(index: usize, mtype: &String) -> UniclipPayload {
-    unsafe {
+// 每个连接一个读任务和一个写任务
+async fn run(handler: Arc<UniclipPeerHandler>, conn: Connection) {
+    let (mut reader, mut writer) = tokio::io::split(conn.stream);
+    let (mut receiver, mut bulk) = (conn.receiver, conn.bulk);
+
+    let key = handler.key.clone();
+    let node_id = handler.node_id.clone();
+    let limiter = handler.limiter.clone();
+    let mut closed = handler.closed.subscribe();
+    let writer_task = tokio::spawn(async move {
         loop {
-            let find = || {
-                let mq = MQ.as_mut_ptr();
-                let mut mq = (*mq).lock().unwrap();
-                let queue = mq.get_mut(mtype).unwrap();
-                for i in 0..queue.len() {
-                    if queue[i].index == index {
-                        let data = queue.remove(i);
-                        return data.data;
+            // 其他数据优先发送且不等待限速, 分块传输的数据在令牌补足后发送
+            let data = tokio::select! {
+                biased;
+                _ = closed.changed() => {
+                    // 关闭前发送队列中剩余的数据
+                    while let Ok(data) = receiver.try_recv().or_else(|_| bulk.try_recv()) {
+                        if write_frame(&mut writer, &key, data).await.is_err() {
+                            break;
+                        }
                     }
+                    break;
                 }
-                UniclipPayload::Error("None".to_string())
+                data = receiver.recv() => data,
+                data = async {
+                    limiter.wait_upload().await;
+                    bulk.recv().await
+                } => data,
             };
-            let data = find();
-            match data {
-                UniclipPayload::Error(..) => {
-                    thread::sleep(time::Duration::from_millis(10));
+            let data = match data {
+                Some(data) => data,
+                None => break,
+            };
+            // 发送进度以写入连接的数据为准, 已取消的传输丢弃队列中剩余的数据
+            let transfer = transfer_frame(&data);
+            if let Some((id, _)) = &transfer {
+                if !progress::running(id, &node_id) {
+                    continue;
                 }
-                _ => {
-                    return data;
+            }
+            let buf = packer::pack(data, &key);
+            if let Err(error) = write_buffer(&mut writer, &buf).await {
+                error!("{}", error);
+                break;
+            }
+            limiter.sent(buf.len());
+            match transfer {
+                Some((id, Some(size))) => {
+                    progress::advance(&id, &node_id, size);
+                    // 加密大的数据块耗时较长, 让出线程以便处理其他连接和控制命令
+                    tokio::task::yield_now().await;
                 }
+                Some((id, None)) => progress::finish(&id, &node_id, TransferState::Finished),
+                None => (),
             }
         }
-    };
+        let _ = writer.shutdown().await;
+    });
+
+    let mut closed = handler.closed.subscribe();
+    loop {
+        let buffer = tokio::select! {
+            buffer = read_buffer(&mut reader) => buffer,
+            _ = closed.changed() => None,
+        };
+        let buffer = match buffer {
+            Some(buffer) => buffer,
+            None => break,
+        };
+        handler.limiter.received(buffer.len());
+        *handler.received.lock().unwrap() = time::Instant::now();
+        let data = packer::unpack(buffer, &handler.key);
+        if let UniclipPayload::ShutDown = data {
+            break;
+        }
+        let transfer = transfer_frame(&data).is_some();
+        if !dispatch(&handler, data).await {
+            break;
+        }
+        // 分块传输的数据超过限速时暂停读取, 由 TCP 流量控制让对方减慢发送,
+        // 未限速时也让出线程, 解密大的数据块时其他连接和控制命令仍能及时处理
+        if transfer {
+            tokio::select! {
+                _ = handler.limiter.wait_download() => (),
+                _ = closed.changed() => break,
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    handler.close();
+    let _ = writer_task.await;
+    // 未完成的接收保留已收到的数据, 对方重连后继续
+    handler.transfers.lock().unwrap().clear();
+    remove_handler(&handler.node_id, handler.index).await;
 }
 
-fn add_handler(mut handler: UniclipPeerHandler) -> usize {
-    unsafe {
-        let handlers = HANDLERS.as_mut_ptr();
-        let mut handlers = (*handlers).lock().unwrap();
-        let handler_ind = handlers.len();
-        handler.set_index(handler_ind);
-        handlers.push(handler);
+async fn remove_handler(node_id: &str, index: usize) {
+    let handler = {
+        let mut handlers = HANDLERS.lock().unwrap();
+        match handlers.get(node_id) {
+            Some(handler) if handler.index == index => (),
+            _ => return,
+        }
+        handlers.remove(node_id).unwrap()
+    };
+    warn!(peer = %handler.remote, node_id = %handler.node_id, "Disconnected");
+    progress::drop_peer(node_id);
 
-        drop(handlers);
+    let update = MEMBERS.lock().unwrap().leave(node_id);
+    if let Some(update) = update {
+        gossip(vec![update], None).await;
+    }
+}
 
-        handle(handler_ind);
-        handler_ind
+async fn gossip(members: Vec<Member>, except: Option<&str>) {
+    let mut tasks = JoinSet::new();
+    for handler in handlers() {
+        if Some(handler.node_id.as_str()) != except {
+            let data = UniclipPayload::Gossip(members.clone());
+            tasks.spawn(async move { handler.send(data).await });
+        }
     }
+    while tasks.join_next().await.is_some() {}
 }
 
-fn add_peer(key: &SharedKey, remote: &RemoteClipboard) {
-    message::success(
-        "success".to_string(),
-        format!("Connected to {}", remote.host),
-    );
-    unsafe {
-        let peers = PEERS.as_mut_ptr();
-        (*peers).lock().unwrap().push(remote.clone());
-        add_handler(UniclipPeerHandler::new(key.clone(), remote.clone()));
-    }
-}
-
-fn add_stream(key: &SharedKey, stream: TcpStream) {
-    let host = stream.peer_addr().unwrap().ip().to_string();
-    let handler = UniclipPeerHandler::from(key.clone(), stream);
-    let rand_a: u32 = random();
-    handler.send(UniclipPayload::Port(rand_a));
-    let index = add_handler(handler);
-    let data = acquire(index, &payload_type::PORT_RES);
-    let port: u16 = match data {
-        UniclipPayload::PortRes(rand_b, port) => {
-            if rand_a + 1 == rand_b {
-                port
-            } else {
-                0
+async fn merge_members(source: &UniclipPeerHandler, members: Vec<Member>) {
+    let mut changes = Vec::new();
+    {
+        let mut members_lock = MEMBERS.lock().unwrap();
+        for mut member in members {
+            // 节点自己的信息不包含地址, 使用连接的地址
+            if member.node_id == source.node_id && member.remote.host.is_empty() {
+                member.remote.host = source.remote.host.clone();
+            }
+            if let Some(update) = members_lock.merge(member) {
+                changes.push(update);
             }
         }
-        _ => {
-            message::error("Invalid uniclip data.".to_string());
-            std::process::exit(-1);
+    }
+
+    if changes.is_empty() {
+        return;
+    }
+    gossip(changes.clone(), Some(&source.node_id)).await;
+
+    // 连接新加入的节点, 保持全连接
+    let local_id = node_id();
+    for member in changes {
+        if member.alive
+            && member.node_id != local_id
+            && !member.remote.host.is_empty()
+            && !is_known(&member.node_id)
+        {
+            connect(member.node_id, member.remote);
         }
-    };
-    let address = RemoteClipboard { host, port };
-    unsafe {
-        let peers = PEERS.as_mut_ptr().read();
-        peers.lock().unwrap().push(address);
-    }
-}
-
-fn get_peers() {
-    unsafe {
-        let handlers = HANDLERS.as_mut_ptr();
-        let handlers = (*handlers).lock().unwrap();
-        if handlers.len() > 0 {
-            let handler = handlers.last().unwrap();
-            drop(handlers);
-
-            let rand_a: u32 = random();
-            handler.send(UniclipPayload::Peer(rand_a));
-            let data = acquire(handler.index, &payload_type::PEER_LIST);
-            println!("DATA: {:?}", data);
-            let peer_list = match data {
-                UniclipPayload::PeerList(rand_b, peer_list) => {
-                    if rand_a + 1 == rand_b {
-                        peer_list
-                    } else {
-                        message::error("Uniclip: PeerList rand error".to_string());
-                        return;
+    }
+}
+
+// 定期检查连接是否存活, 并连接尚未连接的在线节点
+async fn heartbeat() {
+    let mut interval = tokio::time::interval(time::Duration::from_secs(HEARTBEAT_INTERVAL));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        for handler in handlers() {
+            tokio::spawn(async move {
+                let rand_a: u32 = random();
+                let timeout = time::Duration::from_secs(HEARTBEAT_TIMEOUT);
+                let res = handler
+                    .request(
+                        UniclipPayload::Echo(rand_a),
+                        &payload_type::ECHO_RES,
+                        timeout,
+                    )
+                    .await;
+                match res {
+                    UniclipPayload::EchoRes(rand_b) if rand_a.wrapping_add(1) == rand_b => (),
+                    // 限速时回复可能排在分块传输的数据之后, 仍在收到数据时不断开
+                    _ if handler.received.lock().unwrap().elapsed() < timeout => (),
+                    _ => {
+                        warn!("{} is not responding", handler.remote);
+                        handler.close();
                     }
                 }
-                _ => {
-                    message::error("Uniclip: PeerList parse error".to_string());
-                    return;
+            });
+        }
+
+        let members = MEMBERS.lock().unwrap().alive();
+        for member in members {
+            if !member.remote.host.is_empty() && !is_known(&member.node_id) {
+                connect(member.node_id, member.remote);
+            }
+        }
+
+        let peers = STATIC_PEERS.lock().unwrap().clone();
+        for (remote, peer_id) in peers {
+            if !peer_id.is_some_and(|id| is_known(&id) || is_blocked(&id)) {
+                connect(remote.to_string(), remote);
+            }
+        }
+    }
+}
+
+fn connect(peer_id: String, remote: RemoteClipboard) {
+    // 无法直接连接的节点 (例如在 NAT 之后) 等待一段时间后再重试
+    let backoff = time::Duration::from_secs(RECONNECT_BACKOFF);
+    match FAILED.lock().unwrap().get(&peer_id) {
+        Some(failed) if failed.elapsed() < backoff => return,
+        _ => (),
+    }
+    if is_blocked(&peer_id) || !CONNECTING.lock().unwrap().insert(peer_id.clone()) {
+        return;
+    }
+    tokio::spawn(async move {
+        let key = KEY.read().unwrap().clone();
+        if let Some(node_id) = add_peer(&key, &remote).await {
+            FAILED.lock().unwrap().remove(&peer_id);
+            for (static_remote, static_id) in STATIC_PEERS.lock().unwrap().iter_mut() {
+                if *static_remote == remote {
+                    *static_id = Some(node_id.clone());
                 }
-            };
+            }
+        } else {
+            FAILED
+                .lock()
+                .unwrap()
+                .insert(peer_id.clone(), time::Instant::now());
+        }
+        CONNECTING.lock().unwrap().remove(&peer_id);
+    });
+}
+
+// 握手成功时返回对方的节点 ID
+async fn add_peer(key: &SharedKey, remote: &RemoteClipboard) -> Option<String> {
+    let (handler, conn) = UniclipPeerHandler::connect(key.clone(), remote.clone()).await?;
+    let peer_id = handler.node_id.clone();
+    if is_blocked(&peer_id) {
+        return None;
+    }
+    if add_handler(handler, conn).await {
+        info!(peer = %remote, node_id = %peer_id, "Connected");
+    }
+    Some(peer_id)
+}
+
+async fn add_stream(key: &SharedKey, stream: TcpStream) {
+    let (handler, conn) = match UniclipPeerHandler::accept(key.clone(), stream).await {
+        Some(res) => res,
+        None => return,
+    };
+    let peer_id = handler.node_id.clone();
+    let remote = handler.remote.clone();
+    if add_handler(handler, conn).await {
+        info!(peer = %remote, node_id = %peer_id, "Accepted");
+    }
+}
 
-            let peers = PEERS.as_mut_ptr();
-            let peers = (*peers).lock().unwrap();
-            for p in peer_list.iter() {
-                if !peers.contains(p) {
-                    add_peer(&handler.key, p);
+// 启用 TLS 时在 TCP 连接上完成 TLS 握手, 同时返回对方证书的指纹
+async fn secure(stream: TcpStream, outgoing: bool, peer: &str) -> Option<(Stream, Option<String>)> {
+    let tls = match TLS.read().unwrap().clone() {
+        Some(tls) => tls,
+        None => return Some((Box::new(stream), None)),
+    };
+    let timeout = time::Duration::from_secs(HANDSHAKE_TIMEOUT);
+    let res = if outgoing {
+        tokio::time::timeout(timeout, tls.connect(stream)).await
+    } else {
+        tokio::time::timeout(timeout, tls.accept(stream)).await
+    };
+    match res {
+        Ok(Ok((stream, cert))) => Some((stream, Some(cert))),
+        Ok(Err(error)) => {
+            error!("{}: TLS: {}", peer, error);
+            None
+        }
+        Err(_) => {
+            error!("{}: TLS: Timeout", peer);
+            None
+        }
+    }
+}
+
+// 检查对方证书与记录的指纹是否一致
+fn verify_cert(node_id: &str, cert: &Option<String>) -> bool {
+    match (TLS.read().unwrap().as_ref(), cert) {
+        (Some(tls), Some(cert)) => tls.verify(node_id, cert),
+        _ => true,
+    }
+}
+
+// 记录消息 ID, 已经见过时返回 false
+fn mark_seen(id: &str) -> bool {
+    let mut seen = SEEN.lock().unwrap();
+    if seen.iter().any(|s| s == id) {
+        return false;
+    }
+    if seen.len() >= SEEN_LIMIT {
+        seen.pop_front();
+    }
+    seen.push_back(id.to_string());
+    true
+}
+
+// 中继模式下将收到的更新转发给其他节点
+async fn relay(route: UniclipRoute, hash: String, entry: Arc<ClipboardEntry>, source: String) {
+    if !RELAY.load(Ordering::SeqCst) || route.hops >= UNICLIP_MAX_HOPS {
+        return;
+    }
+    let route = UniclipRoute {
+        hops: route.hops + 1,
+        ..route
+    };
+    let mut tasks = JoinSet::new();
+    for handler in handlers() {
+        if handler.node_id != source && handler.node_id != route.origin && handler.can_send() {
+            let (route, hash, entry) = (route.clone(), hash.clone(), entry.clone());
+            tasks.spawn(async move { deliver(&handler, route, hash, &entry).await });
+        }
+    }
+    while tasks.join_next().await.is_some() {}
+}
+
+// 保留延迟发送或获取到的条目, 只保留最近的几个
+fn keep(hash: String, entry: Arc<ClipboardEntry>) {
+    let mut lazy = LAZY.lock().unwrap();
+    if lazy.iter().any(|(h, _)| *h == hash) {
+        return;
+    }
+    if lazy.len() >= LAZY_LIMIT {
+        lazy.pop_front();
+    }
+    lazy.push_back((hash, entry));
+}
+
+fn lazy_entry(hash: &str) -> Option<Arc<ClipboardEntry>> {
+    LAZY.lock()
+        .unwrap()
+        .iter()
+        .find(|(h, _)| h == hash)
+        .map(|(_, entry)| entry.clone())
+}
+
+// 条目信息中附带的文本开头, 敏感内容和文件不附带
+fn preview(entry: &ClipboardEntry) -> String {
+    if entry.sensitive || entry.name.is_some() || entry.is_image() {
+        return String::new();
+    }
+    let text = String::from_utf8_lossy(entry.bytes());
+    text.chars().take(OFFER_PREVIEW_LIMIT).collect()
+}
+
+fn remember(offer: Offer) {
+    let mut offers = OFFERS.lock().unwrap();
+    if offers.len() >= OFFER_LIMIT {
+        offers.pop_front();
+    }
+    offers.push_back(offer);
+}
+
+// 粘贴时提供的格式, 收到的文件为保存的路径
+fn pasted(entry: &ClipboardEntry) -> Vec<(String, Vec<u8>)> {
+    match &entry.name {
+        Some(name) => {
+            let path = received_path(name).display().to_string();
+            vec![(MIME_TEXT.to_string(), path.into_bytes())]
+        }
+        None => entry.formats.clone(),
+    }
+}
+
+// 剪贴板中只写入条目信息, 其他程序粘贴时按 hash 获取内容
+async fn offer_clipboard(handler: &UniclipPeerHandler, offer: &Offer) {
+    let mimes = match offer.info.name {
+        Some(_) => vec![MIME_TEXT.to_string()],
+        None => handler.accepted(&offer.info),
+    };
+    let runtime = tokio::runtime::Handle::current();
+    let offer = offer.clone();
+    let hash = offer.hash.clone();
+    let fetch: clipboard::Fetch = Box::new(move || {
+        let entry = runtime.block_on(fetch_offer(&offer))?;
+        Ok(pasted(&entry))
+    });
+    if let Ok(Err(error)) =
+        tokio::task::spawn_blocking(move || clipboard::offer(&hash, mimes, fetch)).await
+    {
+        debug!("Unable to offer the update in the clipboard: {}", error);
+    }
+}
+
+// 剪贴板中只有收到的条目信息时读取剪贴板会获取内容, 发送条目信息代替
+fn clipboard_offer() -> Option<UniclipPayload> {
+    let hash = clipboard::offered()?;
+    let offer = find_offer(|offer| offer.hash == hash)?;
+    Some(UniclipPayload::Offer(
+        new_route(),
+        offer.hash,
+        offer.info,
+        offer.preview,
+    ))
+}
+
+fn find_offer<F: Fn(&Offer) -> bool>(predicate: F) -> Option<Offer> {
+    OFFERS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|offer| predicate(offer))
+        .cloned()
+}
+
+fn is_fetching(hash: &str) -> bool {
+    FETCHING.lock().unwrap().contains_key(hash)
+}
+
+// 获取的条目在指定时间内收到过分块
+fn fetch_active(hash: &str, within: time::Duration) -> bool {
+    FETCHING
+        .lock()
+        .unwrap()
+        .get(hash)
+        .and_then(|fetching| fetching.active)
+        .is_some_and(|active| active.elapsed() < within)
+}
+
+fn fetch_key(hash: &str) -> String {
+    format!("{} {}", *payload_type::FETCH_RES, hash)
+}
+
+// 中继模式下转发条目信息, 其他节点经本节点获取内容
+async fn relay_offer(offer: Offer) {
+    if !RELAY.load(Ordering::SeqCst) || offer.route.hops >= UNICLIP_MAX_HOPS {
+        return;
+    }
+    let route = UniclipRoute {
+        hops: offer.route.hops + 1,
+        ..offer.route
+    };
+    for handler in handlers() {
+        if handler.node_id != offer.source && handler.node_id != route.origin && handler.can_send()
+        {
+            let data = UniclipPayload::Offer(
+                route.clone(),
+                offer.hash.clone(),
+                offer.info.clone(),
+                offer.preview.clone(),
+            );
+            handler.send(data).await;
+        }
+    }
+}
+
+// 获取条目的内容, 来源节点直接连接时从来源节点获取, 否则从转发的节点获取.
+// 内容经普通的更新发送, 收到后写入剪贴板
+async fn fetch_offer(offer: &Offer) -> Result<Arc<ClipboardEntry>, String> {
+    let handler = {
+        let handlers = HANDLERS.lock().unwrap();
+        [&offer.route.origin, &offer.source]
+            .iter()
+            .filter_map(|node_id| handlers.get(*node_id))
+            .find(|handler| handler.can_recv())
+            .cloned()
+    };
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return Err(format!("{} is not connected", offer.route.origin)),
+    };
+    let (sender, mut receiver) = oneshot::channel();
+    // 同一条目同时只请求一次
+    let first = {
+        let mut fetching = FETCHING.lock().unwrap();
+        let waiters = &mut fetching.entry(offer.hash.clone()).or_default().waiters;
+        waiters.push(sender);
+        waiters.len() == 1
+    };
+    if first {
+        let timeout = time::Duration::from_secs(TRANSFER_TIMEOUT);
+        let res = handler
+            .request(
+                UniclipPayload::Fetch(offer.hash.clone()),
+                &fetch_key(&offer.hash),
+                timeout,
+            )
+            .await;
+        let error = match res {
+            UniclipPayload::FetchRes(_, true) => None,
+            UniclipPayload::FetchRes(_, false) => Some("Content is no longer available".into()),
+            UniclipPayload::Error(error) => Some(error),
+            _ => Some("Invalid response".to_string()),
+        };
+        if let Some(error) = error {
+            FETCHING.lock().unwrap().remove(&offer.hash);
+            return Err(error);
+        }
+    }
+    let timeout = time::Duration::from_secs(FETCH_TIMEOUT);
+    let error = loop {
+        match tokio::time::timeout(timeout, &mut receiver).await {
+            Ok(Ok(_)) => break None,
+            Ok(Err(_)) => break Some("Fetch failed"),
+            Err(_) if fetch_active(&offer.hash, timeout) => (),
+            Err(_) => break Some("Fetch timed out"),
+        }
+    };
+    if let Some(error) = error {
+        FETCHING.lock().unwrap().remove(&offer.hash);
+        return Err(error.to_string());
+    }
+    lazy_entry(&offer.hash).ok_or_else(|| "Fetch failed".to_string())
+}
+
+// 返回发送的节点数
+async fn broadcast(entry: Arc<ClipboardEntry>) -> usize {
+    let route = new_route();
+    let hash = hash_entry(&entry).await;
+    // 超过设置大小的条目只发送条目信息, 对方需要时再获取内容
+    let lazy = LAZY_SIZE
+        .read()
+        .unwrap()
+        .is_some_and(|size| entry.size() > size);
+    let offer = match lazy {
+        true => {
+            keep(hash.clone(), entry.clone());
+            Some(UniclipPayload::Offer(
+                route.clone(),
+                hash.clone(),
+                entry.info(),
+                preview(&entry),
+            ))
+        }
+        false => None,
+    };
+    let mut tasks = JoinSet::new();
+    for handler in handlers() {
+        if handler.can_send() {
+            let (route, hash, entry) = (route.clone(), hash.clone(), entry.clone());
+            let offer = offer.clone();
+            tasks.spawn(async move {
+                match offer {
+                    Some(offer) => handler.send(offer).await,
+                    None => deliver(&handler, route, hash, &entry).await,
                 }
+            });
+        }
+    }
+    let count = tasks.len();
+    while tasks.join_next().await.is_some() {}
+    count
+}
+
+// 由本节点发出的更新
+fn new_route() -> UniclipRoute {
+    let route = UniclipRoute {
+        id: Uuid::new_v4().to_string(),
+        origin: node_id(),
+        hops: 0,
+    };
+    mark_seen(&route.id);
+    route
+}
+
+fn transfer_key(id: &str) -> String {
+    format!("{} {}", *payload_type::UPDATE_BIG_ACK, id)
+}
+
+// 中断后可以继续的发送
+#[derive(Clone)]
+struct Outgoing {
+    node_id: String,
+    route: UniclipRoute,
+    hash: String,
+    entry: Arc<ClipboardEntry>,
+    started: time::Instant,
+}
+
+// 发送完成或取消后不再需要继续, 连接断开导致的失败保留
+fn forget_outgoing(progress: &progress::Progress) {
+    if !progress.sending
+        || matches!(
+            progress.state,
+            TransferState::Running | TransferState::Failed
+        )
+    {
+        return;
+    }
+    OUTGOING
+        .lock()
+        .unwrap()
+        .remove(&format!("{} {}", progress.id, progress.node_id));
+}
+
+// 对方重新连接后继续中断的发送, 对方从已收到的分块之后开始接收
+async fn resume(handler: Arc<UniclipPeerHandler>) {
+    let items: Vec<Outgoing> = {
+        let mut outgoing = OUTGOING.lock().unwrap();
+        let timeout = time::Duration::from_secs(RESUME_TIMEOUT);
+        outgoing.retain(|_, item| item.started.elapsed() < timeout);
+        let keys: Vec<String> = outgoing
+            .iter()
+            .filter(|(_, item)| item.node_id == handler.node_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter().filter_map(|key| outgoing.remove(key)).collect()
+    };
+    if items.is_empty() {
+        return;
+    }
+    tokio::time::sleep(time::Duration::from_secs(RESUME_DELAY)).await;
+    for item in items {
+        if !handler.can_send() {
+            continue;
+        }
+        debug!(node_id = %handler.node_id, "Resuming update {}", item.route.id);
+        deliver(&handler, item.route, item.hash, &item.entry).await;
+    }
+}
+
+// 小的条目直接发送, 大的条目在对方确认后分块发送
+async fn deliver(
+    handler: &UniclipPeerHandler,
+    route: UniclipRoute,
+    hash: String,
+    entry: &Arc<ClipboardEntry>,
+) {
+    if entry.size() <= UNICLIP_BIG_LIMIT {
+        let data = UniclipPayload::Update(route, hash, entry.as_ref().clone());
+        handler.send(data).await;
+        return;
+    }
+    // 先发送条目信息和分块列表, 对方只请求缓存中没有的分块
+    let hashes = {
+        let entry = entry.clone();
+        let hash_chunks =
+            move || -> Vec<String> { entry.chunks().into_iter().map(packer::hash).collect() };
+        match tokio::task::spawn_blocking(hash_chunks).await {
+            Ok(hashes) => hashes,
+            Err(_) => return,
+        }
+    };
+    let id = route.id.clone();
+    let outgoing = Outgoing {
+        node_id: handler.node_id.clone(),
+        route: route.clone(),
+        hash: hash.clone(),
+        entry: entry.clone(),
+        started: time::Instant::now(),
+    };
+    let timeout = time::Duration::from_secs(TRANSFER_TIMEOUT + CONFIRM_TIMEOUT);
+    let res = handler
+        .request(
+            UniclipPayload::UpdateBig(route, hash, entry.info(), hashes),
+            &transfer_key(&id),
+            timeout,
+        )
+        .await;
+    let needed = match res {
+        UniclipPayload::UpdateBigAck(_, true, needed) => needed,
+        UniclipPayload::UpdateBigAck(_, false, _) => return,
+        UniclipPayload::Error(error) => {
+            warn!("{}: {}", handler.remote, error);
+            return;
+        }
+        _ => return,
+    };
+    let chunks = entry.chunks();
+    if needed.iter().any(|index| *index >= chunks.len()) {
+        warn!("{}: Invalid chunk index", handler.remote);
+        return;
+    }
+    let skipped = entry.size()
+        - needed
+            .iter()
+            .map(|index| chunks[*index].len())
+            .sum::<usize>();
+    OUTGOING
+        .lock()
+        .unwrap()
+        .insert(format!("{} {}", id, handler.node_id), outgoing);
+    // 写任务更新进度并在写入结束帧后完成
+    let cancel = progress::start(&id, &handler.node_id, true, skipped, entry.size());
+    for index in needed {
+        if cancel.load(Ordering::SeqCst) {
+            return;
+        }
+        let chunk = chunks[index].to_vec();
+        handler
+            .send(UniclipPayload::UpdateBigData(id.clone(), index, chunk))
+            .await;
+    }
+    handler.send(UniclipPayload::UpdateBigFinish(id)).await;
+}
+
+pub struct HistoryEntry {
+    pub time: time::SystemTime, // received time
+    pub source: String,         // node id of the sender
+    pub entry: Arc<ClipboardEntry>,
+}
+
+#[derive(Clone)]
+pub struct Confirmation {
+    pub id: String,      // message id
+    pub node_id: String, // node id of the sender
+    pub info: EntryInfo,
+}
+
+// 等待获取的任务, 以及最近收到分块的时间, 用于判断传输是否仍在进行
+#[derive(Default)]
+struct Fetching {
+    waiters: Vec<oneshot::Sender<()>>,
+    active: Option<time::Instant>, // time the last chunk was received
+}
+
+// 只收到条目信息的更新
+#[derive(Clone)]
+pub struct Offer {
+    pub route: UniclipRoute,
+    pub hash: String,
+    pub info: EntryInfo,
+    pub preview: String, // beginning of the text
+    pub source: String,  // node id of the peer it was received from
+}
+
+// 等待用户通过控制接口确认, 超时视为拒绝
+async fn confirm(id: &str, handler: &UniclipPeerHandler, info: EntryInfo) -> bool {
+    let (sender, receiver) = oneshot::channel();
+    let size = info.size();
+    let item = Confirmation {
+        id: id.to_string(),
+        node_id: handler.node_id.clone(),
+        info,
+    };
+    CONFIRMS
+        .lock()
+        .unwrap()
+        .insert(id.to_string(), (item, sender));
+    warn!(
+        node_id = %handler.node_id,
+        bytes = size,
+        "Update {} waits for confirmation, accept or reject it with the control commands",
+        id
+    );
+    let timeout = time::Duration::from_secs(CONFIRM_TIMEOUT);
+    let res = tokio::time::timeout(timeout, receiver).await;
+    CONFIRMS.lock().unwrap().remove(id);
+    let error = match res {
+        Ok(Ok(true)) => return true,
+        Ok(_) => "Rejected by the user",
+        Err(_) => "Confirmation timed out",
+    };
+    handler.send(UniclipPayload::Error(error.to_string())).await;
+    false
+}
+
+pub struct Status {
+    pub node_id: String,
+    pub listen: Vec<SocketAddr>,
+    pub peers: usize,
+    pub members: usize,
+    pub relay: bool,
+    pub fingerprint: Option<String>,
+    pub blocked: usize,
+    pub last_blocked: Option<(time::SystemTime, String)>,
+}
+
+pub struct PeerStatus {
+    pub node_id: String,
+    pub remote: RemoteClipboard,
+    pub outgoing: bool,
+    pub can_send: bool,
+    pub can_recv: bool,
+}
+
+pub fn status() -> Status {
+    Status {
+        node_id: node_id(),
+        listen: LISTEN.read().unwrap().clone(),
+        peers: HANDLERS.lock().unwrap().len(),
+        members: MEMBERS.lock().unwrap().alive().len(),
+        relay: RELAY.load(Ordering::SeqCst),
+        fingerprint: TLS
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|tls| tls.fingerprint().to_string()),
+        blocked: BLOCKED_SECRETS.load(Ordering::SeqCst),
+        last_blocked: LAST_BLOCKED.lock().unwrap().clone(),
+    }
+}
+
+pub fn peers() -> Vec<PeerStatus> {
+    let mut peers: Vec<PeerStatus> = handlers()
+        .iter()
+        .map(|handler| PeerStatus {
+            node_id: handler.node_id.clone(),
+            remote: handler.remote.clone(),
+            outgoing: handler.outgoing,
+            can_send: handler.can_send(),
+            can_recv: handler.can_recv(),
+        })
+        .collect();
+    peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    peers
+}
+
+pub fn history() -> Vec<HistoryEntry> {
+    HISTORY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|entry| HistoryEntry {
+            time: entry.time,
+            source: entry.source.clone(),
+            entry: entry.entry.clone(),
+        })
+        .collect()
+}
+
+pub fn pending() -> Vec<Confirmation> {
+    let mut pending: Vec<Confirmation> = CONFIRMS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(item, _)| item.clone())
+        .collect();
+    pending.sort_by_key(|item| item.info.time);
+    pending
+}
+
+pub fn offers() -> Vec<Offer> {
+    OFFERS.lock().unwrap().iter().cloned().collect()
+}
+
+// 获取只收到条目信息的更新并写入剪贴板, id 可以只写开头部分, 为空时获取最近的一个
+pub async fn fetch(id: &str) -> Result<String, String> {
+    let matched: Vec<Offer> = OFFERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|offer| offer.route.id.starts_with(id))
+        .cloned()
+        .collect();
+    let offer = match &matched[..] {
+        [] if id.is_empty() => return Err("No update is offered".to_string()),
+        [] => return Err(format!("No offered update matches {}", id)),
+        [.., last] if id.is_empty() => last,
+        [offer] => offer,
+        _ => return Err(format!("{} matches more than one offered update", id)),
+    };
+    fetch_offer(offer).await?;
+    Ok(offer.route.id.clone())
+}
+
+// 确认或拒绝等待中的更新, id 可以只写开头部分
+pub fn answer(id: &str, accept: bool) -> Result<String, String> {
+    let mut confirms = CONFIRMS.lock().unwrap();
+    let keys: Vec<String> = confirms
+        .keys()
+        .filter(|key| key.starts_with(id))
+        .cloned()
+        .collect();
+    match &keys[..] {
+        [key] => {
+            let (_, sender) = confirms.remove(key).unwrap();
+            let _ = sender.send(accept);
+            Ok(key.clone())
+        }
+        [] => Err(format!("No pending update matches {}", id)),
+        _ => Err(format!("{} matches more than one pending update", id)),
+    }
+}
+
+// 敏感内容默认不发送, 设置了清除时间时标记后发送
+fn guard(mut entry: ClipboardEntry) -> Result<ClipboardEntry, String> {
+    let reason = match secret::detect(&entry) {
+        Some(reason) => reason,
+        None => return Ok(entry),
+    };
+    match secret::clear_after() {
+        Some(seconds) => {
+            entry.sensitive = true;
+            entry.clear_after = Some(seconds);
+            Ok(entry)
+        }
+        None => {
+            BLOCKED_SECRETS.fetch_add(1, Ordering::SeqCst);
+            *LAST_BLOCKED.lock().unwrap() = Some((time::SystemTime::now(), reason.clone()));
+            Err(format!(
+                "Content looks like a secret ({}), not sent",
+                reason
+            ))
+        }
+    }
+}
+
+// 取消正在进行的分块传输, id 可以是消息 ID 的前缀, 发给多个节点时全部取消
+pub async fn cancel(id: &str) -> Result<String, String> {
+    let matched: Vec<progress::Progress> = progress::list()
+        .into_iter()
+        .filter(|item| item.id.starts_with(id))
+        .collect();
+    let ids: HashSet<&String> = matched.iter().map(|item| &item.id).collect();
+    let id = match ids.len() {
+        0 => return Err(format!("No transfer matches {}", id)),
+        1 => ids.into_iter().next().unwrap().clone(),
+        _ => return Err(format!("{} matches more than one transfer", id)),
+    };
+    for item in matched.iter() {
+        // 发送任务检查取消标志后停止, 写任务丢弃队列中剩余的数据
+        progress::cancel(&item.id, &item.node_id);
+        progress::finish(&item.id, &item.node_id, TransferState::Cancelled);
+        let handler = HANDLERS.lock().unwrap().get(&item.node_id).cloned();
+        if let Some(handler) = handler {
+            let transfer = handler.transfers.lock().unwrap().remove(&item.id);
+            if let Some(transfer) = transfer {
+                transfer.partial.discard();
+            }
+            handler
+                .send(UniclipPayload::UpdateBigCancel(item.id.clone()))
+                .await;
+        }
+    }
+    Ok(id)
+}
+
+// 将本地剪贴板发送给所有节点, 返回发送的节点数
+pub async fn push() -> Result<usize, String> {
+    if let Some(offer) = clipboard_offer() {
+        let mut count = 0;
+        for handler in handlers() {
+            if handler.can_send() {
+                handler.send(offer.clone()).await;
+                count += 1;
             }
         }
+        return Ok(count);
+    }
+    let local_id = node_id();
+    let entry = match tokio::task::spawn_blocking(move || clipboard::get(&local_id)).await {
+        Ok(entry) => entry,
+        Err(_) => return Ok(0),
+    };
+    if let Some(text) = entry.get(MIME_TEXT) {
+        let text = String::from_utf8_lossy(text);
+        debug!(content = %logging::redact(&text), "Read clipboard data");
+    }
+    send(entry).await
+}
+
+// 粘贴时读取剪贴板, 无头模式下剪贴板中只有条目信息时先获取内容, 获取失败时不再重试.
+// 其他模式下由读取剪贴板时的选区请求获取
+pub async fn paste() -> Result<ClipboardEntry, String> {
+    if let Ok(Err(error)) = tokio::task::spawn_blocking(clipboard::fetch_offered).await {
+        return Err(error);
+    }
+    Ok(current().await)
+}
+
+// 读取本地剪贴板, 无头模式下为最近收到的内容
+pub async fn current() -> ClipboardEntry {
+    let local_id = node_id();
+    tokio::task::spawn_blocking(move || clipboard::get(&local_id))
+        .await
+        .unwrap_or_else(|_| ClipboardEntry::from_text(&node_id(), String::new()))
+}
+
+// 发送指定的内容, 不经过本地剪贴板
+pub async fn send(entry: ClipboardEntry) -> Result<usize, String> {
+    let entry = Arc::new(guard(entry)?);
+    record(&node_id(), &entry);
+    Ok(broadcast(entry).await)
+}
+
+// 等待下一个收到的更新
+pub async fn recv() -> Option<Arc<ClipboardEntry>> {
+    INCOMING.subscribe().recv().await.ok()
+}
+
+// 请求其他节点发送剪贴板, 返回请求的节点数
+pub async fn pull() -> usize {
+    let mut count = 0;
+    for handler in handlers() {
+        if handler.can_recv() {
+            handler.send(UniclipPayload::Pull).await;
+            count += 1;
+        }
     }
+    count
 }
 
-fn broadcast(data: UniclipPayload) {
-    unsafe {
-        let handlers = HANDLERS.as_mut_ptr();
-        let handlers = (*handlers).lock().unwrap();
-        for handler in handlers.iter() {
-            handler.send(data.clone());
+// 手动连接节点, 同时取消之前的手动断开
+pub async fn dial(remote: RemoteClipboard) -> Result<String, String> {
+    let key = KEY.read().unwrap().clone();
+    let (handler, conn) = match UniclipPeerHandler::connect(key, remote.clone()).await {
+        Some(res) => res,
+        None => return Err(format!("Unable to connect to {}", remote)),
+    };
+    let peer_id = handler.node_id.clone();
+    BLOCKED.lock().unwrap().remove(&peer_id);
+    if add_handler(handler, conn).await {
+        info!("Connected to {} ({})", remote, peer_id);
+    }
+    Ok(peer_id)
+}
+
+// 按节点 ID, host 或 host:port 断开连接, 返回断开的节点 ID
+pub fn disconnect(target: &str) -> Vec<String> {
+    let mut closed = Vec::new();
+    for handler in handlers() {
+        if handler.node_id == target
+            || handler.remote.host == target
+            || handler.remote.to_string() == target
+        {
+            BLOCKED.lock().unwrap().insert(handler.node_id.clone());
+            handler.close();
+            closed.push(handler.node_id.clone());
         }
     }
+    closed
+}
+
+pub fn request_shutdown() {
+    SHUTDOWN.notify_one();
+}
+
+pub async fn wait_shutdown() {
+    SHUTDOWN.notified().await;
+}
+
+// 通知其他节点本节点离开, 然后关闭所有连接
+pub async fn shutdown() {
+    let update = MEMBERS.lock().unwrap().quit();
+    gossip(vec![update], None).await;
+    for handler in handlers() {
+        handler.close();
+    }
+    let deadline = time::Instant::now() + time::Duration::from_secs(SHUTDOWN_TIMEOUT);
+    while !HANDLERS.lock().unwrap().is_empty() && time::Instant::now() < deadline {
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+    }
+}
+
+pub struct Connection {
+    stream: Stream,
+    receiver: mpsc::Receiver<UniclipPayload>,
+    bulk: mpsc::Receiver<UniclipPayload>,
 }
 
 pub struct UniclipPeerHandler {
     key: SharedKey,
     index: usize,
-    stream: TcpStream,
+    node_id: String,
+    outgoing: bool,
+    remote: RemoteClipboard,
+    groups: Mutex<Vec<String>>,
+    sender: mpsc::Sender<UniclipPayload>,
+    bulk: mpsc::Sender<UniclipPayload>, // data of chunked transfers
+    limiter: Arc<Limiter>,
+    received: Mutex<time::Instant>, // time of the last received frame
+    pending: Mutex<HashMap<String, oneshot::Sender<UniclipPayload>>>,
+    // 正在接收的分块更新, 以消息 ID 为键
+    transfers: Mutex<HashMap<String, Transfer>>,
+    closed: watch::Sender<bool>,
 }
 
-impl UniclipPeerHandler {
-    pub fn new(key: SharedKey, remote: RemoteClipboard) -> UniclipPeerHandler {
-        let stream = TcpStream::connect(format!("{}:{}", remote.host, remote.port));
-        let stream = match stream {
-            Ok(stream) => stream,
-            Err(error) => {
-                message::error(format!("{}", error));
-                std::process::exit(-1);
+struct Transfer {
+    route: UniclipRoute,
+    hash: String,
+    info: EntryInfo,
+    partial: partial::Partial,
+    hashes: Vec<String>,       // hash of each chunk
+    requested: HashSet<usize>, // chunks sent by the peer, the others are read from the cache
+    pin: Option<chunks::Pin>,  // keeps the cached chunks until the transfer is dropped
+}
+
+// 分块缓存和未完成的数据都在文件中, 以下方法在阻塞线程中调用.
+// 敏感的条目不使用缓存
+impl Transfer {
+    // 固定需要的分块后写入缓存中已有的分块, 返回还需要对方发送的分块
+    fn start(mut self) -> (Self, Result<Vec<usize>, String>) {
+        if !self.info.sensitive {
+            self.pin = Some(chunks::pin(&self.hashes));
+        }
+        let needed = self.needed();
+        self.requested = needed.iter().copied().collect();
+        let res = self.fill().map(|_| needed);
+        (self, res)
+    }
+
+    // 从缓存中依次写入不需要对方发送的分块, 返回写入的字节数
+    fn fill(&mut self) -> Result<usize, String> {
+        let mut size = 0;
+        while !self.partial.complete() {
+            let index = self.partial.next_chunk();
+            if self.requested.contains(&index) {
+                break;
             }
-        };
-        UniclipPeerHandler {
-            key,
-            index: 0,
-            stream,
+            let data = match chunks::get(&self.hashes[index]) {
+                Some(data) => data,
+                None => return Err(format!("Chunk {} is missing from the cache", index)),
+            };
+            self.partial.write(index, &data)?;
+            size += data.len();
         }
+        Ok(size)
+    }
+
+    // 还需要对方发送的分块, 条目中重复的分块只请求第一个, 之后从缓存中写入
+    fn needed(&self) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        (self.partial.next_chunk()..self.hashes.len())
+            .filter(|index| {
+                let hash = &self.hashes[*index];
+                self.info.sensitive || (!chunks::has(hash) && seen.insert(hash))
+            })
+            .collect()
     }
 
-    pub fn from(key: SharedKey, stream: TcpStream) -> UniclipPeerHandler {
+    // 校验并写入收到的分块, 之后写入缓存中已有的分块, 返回写入的字节数
+    fn write(&mut self, index: usize, chunk: &[u8]) -> Result<usize, String> {
+        match self.hashes.get(index) {
+            Some(hash) if *hash == packer::hash(chunk) => (),
+            _ => return Err(format!("Chunk {} hash error", index)),
+        }
+        self.partial.write(index, chunk)?;
+        if !self.info.sensitive {
+            chunks::put(&self.hashes[index], chunk);
+        }
+        Ok(chunk.len() + self.fill()?)
+    }
+}
+
+impl UniclipPeerHandler {
+    fn new(
+        key: SharedKey,
+        stream: Stream,
+        node_id: String,
+        remote: RemoteClipboard,
+        outgoing: bool,
+    ) -> (UniclipPeerHandler, Connection) {
+        let (sender, receiver) = mpsc::channel(SEND_QUEUE_LIMIT);
+        let (bulk, bulk_receiver) = mpsc::channel(BULK_QUEUE_LIMIT);
+        let limiter = Arc::new(Limiter::new(&node_id, &remote));
         let handler = UniclipPeerHandler {
             key,
-            index: 0,
+            index: NEXT_INDEX.fetch_add(1, Ordering::SeqCst),
+            node_id,
+            outgoing,
+            remote,
+            groups: Mutex::new(Vec::new()),
+            sender,
+            bulk,
+            limiter,
+            received: Mutex::new(time::Instant::now()),
+            pending: Mutex::new(HashMap::new()),
+            transfers: Mutex::new(HashMap::new()),
+            closed: watch::channel(false).0,
+        };
+        let conn = Connection {
             stream,
+            receiver,
+            bulk: bulk_receiver,
         };
-        handler
+        (handler, conn)
     }
 
-    pub fn set_index(&mut self, index: usize) {
-        self.index = index;
-    }
-
-    pub fn send(&self, data: UniclipPayload) {
-        let key = &self.key;
-        let mut stream = &self.stream;
+    // 主动连接, 发送 Hello 并等待 HelloRes
+    pub async fn connect(
+        key: SharedKey,
+        remote: RemoteClipboard,
+    ) -> Option<(UniclipPeerHandler, Connection)> {
+        let addr = (remote.host.as_str(), remote.port);
+        let stream = match TcpStream::connect(addr).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                error!("{}: {}", remote, error);
+                return None;
+            }
+        };
+        let (mut stream, cert) = secure(stream, true, &remote.to_string()).await?;
 
-        let buf = packer::pack(data, key);
-        let buffer = buf.as_slice();
+        let local_id = node_id();
+        let rand_a: u32 = random();
+        let hello = UniclipPayload::Hello(rand_a, local_id.clone(), PORT.load(Ordering::SeqCst));
+        if write_frame(&mut stream, &key, hello).await.is_err() {
+            return None;
+        }
+        let timeout = time::Duration::from_secs(HANDSHAKE_TIMEOUT);
+        let res = tokio::time::timeout(timeout, read_frame(&mut stream, &key)).await;
+        match res {
+            Ok(UniclipPayload::HelloRes(rand_b, peer_id, _))
+                if rand_a.wrapping_add(1) == rand_b =>
+            {
+                if peer_id == local_id {
+                    warn!("{} is this node, skipped", remote);
+                    return None;
+                }
+                if !verify_cert(&peer_id, &cert) {
+                    error!(
+                        "{}: Certificate of {} does not match the pinned one",
+                        remote, peer_id
+                    );
+                    return None;
+                }
+                Some(Self::new(key, stream, peer_id, remote, true))
+            }
+            Ok(UniclipPayload::Error(error)) => {
+                warn!("{}: {}", remote, error);
+                None
+            }
+            _ => {
+                error!("{}: Invalid handshake", remote);
+                None
+            }
+        }
+    }
 
-        let res = stream.write(&buffer);
+    // 接受连接, 等待 Hello 并回复 HelloRes
+    pub async fn accept(
+        key: SharedKey,
+        stream: TcpStream,
+    ) -> Option<(UniclipPeerHandler, Connection)> {
+        let host = match stream.peer_addr() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => String::new(),
+        };
+        let (mut stream, cert) = secure(stream, false, &host).await?;
 
+        let local_id = node_id();
+        let timeout = time::Duration::from_secs(HANDSHAKE_TIMEOUT);
+        let res = tokio::time::timeout(timeout, read_frame(&mut stream, &key)).await;
         match res {
-            Ok(_) => (),
-            Err(error) => {
-                message::error(format!("{}", error));
+            Ok(UniclipPayload::Hello(rand_a, peer_id, port)) => {
+                if peer_id == local_id {
+                    let error = UniclipPayload::Error("Self connection".to_string());
+                    let _ = write_frame(&mut stream, &key, error).await;
+                    return None;
+                }
+                if is_blocked(&peer_id) {
+                    let error = UniclipPayload::Error("Disconnected by peer".to_string());
+                    let _ = write_frame(&mut stream, &key, error).await;
+                    return None;
+                }
+                if !verify_cert(&peer_id, &cert) {
+                    error!(
+                        "{}: Certificate of {} does not match the pinned one",
+                        host, peer_id
+                    );
+                    let error = UniclipPayload::Error("Certificate mismatch".to_string());
+                    let _ = write_frame(&mut stream, &key, error).await;
+                    return None;
+                }
+                let hello = UniclipPayload::HelloRes(
+                    rand_a.wrapping_add(1),
+                    local_id,
+                    PORT.load(Ordering::SeqCst),
+                );
+                if write_frame(&mut stream, &key, hello).await.is_err() {
+                    return None;
+                }
+                let remote = RemoteClipboard { host, port };
+                Some(Self::new(key, stream, peer_id, remote, false))
+            }
+            // 版本不同等错误告知对方, 对方不必等到超时
+            Ok(UniclipPayload::Error(error)) => {
+                warn!("{}: {}", host, error);
+                let error = UniclipPayload::Error(error);
+                let _ = write_frame(&mut stream, &key, error).await;
+                None
+            }
+            _ => {
+                error!("{}: Invalid handshake", host);
+                None
             }
         }
     }
 
-    pub fn recv(&self) -> UniclipPayload {
-        let key = &self.key;
-        let mut stream = &self.stream;
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
 
-        let mut buffer = [0; UNICLIP_DATA_LIMIT];
-        let res = stream.read(&mut buffer);
+    pub fn can_send(&self) -> bool {
+        let groups = self.groups.lock().unwrap();
+        POLICY
+            .read()
+            .unwrap()
+            .can_send(&self.node_id, &self.remote, &groups)
+    }
 
-        let size = match res {
-            Ok(s) => s,
-            Err(error) => {
-                message::error(format!("{}", error));
-                0
-            }
-        };
+    pub fn can_recv(&self) -> bool {
+        let groups = self.groups.lock().unwrap();
+        POLICY
+            .read()
+            .unwrap()
+            .can_recv(&self.node_id, &self.remote, &groups)
+    }
 
-        let buf = &buffer[0..size];
-        let data = packer::unpack(Vec::from(buf), key);
-        data
+    // 检查是否接收该条目, 拒绝时返回原因
+    pub fn admit(&self, info: &EntryInfo) -> Result<(), String> {
+        if !self.can_recv() {
+            return Err(format!("Sync from {} is not allowed", self.remote));
+        }
+        let policy = POLICY.read().unwrap();
+        policy
+            .receive
+            .check(&self.node_id, &self.remote, info)
+            .map(|_| ())
     }
 
-    pub fn recv_big(&self, big_size: usize) -> UniclipPayload {
-        let key = &self.key;
-        let mut stream = &self.stream;
+    // 可以接收的格式
+    pub fn accepted(&self, info: &EntryInfo) -> Vec<String> {
+        let policy = POLICY.read().unwrap();
+        policy
+            .receive
+            .check(&self.node_id, &self.remote, info)
+            .unwrap_or_default()
+    }
 
-        let mut buffer = vec![0u8; big_size + UNICLIP_DATA_LIMIT];
-        let res = stream.read(&mut buffer);
+    pub fn needs_confirm(&self, info: &EntryInfo) -> bool {
+        let policy = POLICY.read().unwrap();
+        policy
+            .receive
+            .confirm_size(&self.node_id, &self.remote)
+            .is_some_and(|size| info.size() > size)
+    }
 
-        match res {
-            Ok(_) => (),
+    async fn start_transfer(&self, id: String, transfer: Transfer, accepted: bool) {
+        let (transfer, res) = match accepted {
+            true => match tokio::task::spawn_blocking(move || transfer.start()).await {
+                Ok(res) => res,
+                Err(_) => return,
+            },
+            false => (transfer, Err("Rejected".to_string())),
+        };
+        let needed = match res {
+            Ok(needed) => needed,
             Err(error) => {
-                message::error(format!("{}", error));
+                if accepted {
+                    error!("{}: {}", self.remote, error);
+                }
+                transfer.partial.discard();
+                let data = UniclipPayload::UpdateBigAck(id, false, Vec::new());
+                self.send(data).await;
+                return;
             }
+        };
+        let partial = &transfer.partial;
+        progress::start(&id, &self.node_id, false, partial.received, partial.size);
+        self.transfers.lock().unwrap().insert(id.clone(), transfer);
+        self.send(UniclipPayload::UpdateBigAck(id, true, needed))
+            .await;
+    }
+
+    pub async fn send(&self, data: UniclipPayload) {
+        // 连接已关闭时丢弃, 结束帧与数据使用同一队列以保证顺序
+        let sender = match transfer_frame(&data) {
+            Some(_) => &self.bulk,
+            None => &self.sender,
+        };
+        let _ = sender.send(data).await;
+    }
+
+    // 发送请求并等待指定类型的回复
+    pub async fn request(
+        &self,
+        data: UniclipPayload,
+        mtype: &String,
+        timeout: time::Duration,
+    ) -> UniclipPayload {
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(mtype.clone(), sender);
+        self.send(data).await;
+        let res = tokio::time::timeout(timeout, receiver).await;
+        self.pending.lock().unwrap().remove(mtype);
+        match res {
+            Ok(Ok(data)) => data,
+            Ok(Err(_)) => UniclipPayload::Error("Peer disconnected".to_string()),
+            Err(_) => UniclipPayload::Error("Timeout".to_string()),
         }
+    }
 
-        let data = packer::unpack(Vec::from(buffer), key);
-        data
+    fn respond(&self, mtype: &String, data: UniclipPayload) {
+        let sender = self.pending.lock().unwrap().remove(mtype);
+        if let Some(sender) = sender {
+            let _ = sender.send(data);
+        }
     }
 }
 
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // IPv6 地址只监听 IPv6, 以便与 0.0.0.0 同时监听同一端口
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
 pub struct Uniclip {
+    node_id: String,
+    headless: bool,
     port: u16,
+    listen: Vec<SocketAddr>,
     key: SharedKey,
-    hotkey: Vec<hotkey::Keycode>,
+    hotkey: HotkeyConfig,
+    peers: Vec<RemoteClipboard>,
+    discovery: Option<DiscoveryConfig>,
 }
 
 impl Uniclip {
-    pub fn new(local_clip: &LocalClipboard, hotkey: Vec<hotkey::Keycode>) -> Uniclip {
+    pub fn new(local_clip: &LocalClipboard) -> Uniclip {
         let key = packer::pwd2key(local_clip.password.clone());
 
-        if local_clip.peer.port != 0 {
-            add_peer(&key, &local_clip.peer);
-            get_peers();
+        PORT.store(local_clip.port, Ordering::SeqCst);
+        *NODE_ID.write().unwrap() = local_clip.node_id.clone();
+        *POLICY.write().unwrap() = local_clip.policy.clone();
+        *KEY.write().unwrap() = key.clone();
+        RELAY.store(local_clip.relay, Ordering::SeqCst);
+        *CLEAR.write().unwrap() = local_clip.clear.clone();
+        *LAZY_SIZE.write().unwrap() = local_clip.lazy_size;
+        *MEMBERS.lock().unwrap() = Membership::new(&local_clip.node_id, local_clip.port);
+        *LISTEN.write().unwrap() = local_clip.listen.clone();
+        *RECEIVE_DIR.write().unwrap() = local_clip.data_dir.join(RECEIVE_DIR_NAME);
+        partial::init(local_clip.data_dir.join(PARTIAL_DIR_NAME));
+        chunks::init(local_clip.data_dir.join(CHUNKS_DIR_NAME));
+        throttle::init(&local_clip.bandwidth);
+
+        let tls = local_clip
+            .tls
+            .as_ref()
+            .map(|config| match tls::Tls::load(config) {
+                Ok(tls) => Arc::new(tls),
+                Err(error) => {
+                    error!("TLS: {}", error);
+                    std::process::exit(-1);
+                }
+            });
+        if let Some(tls) = &tls {
+            info!("TLS certificate fingerprint: {}", tls.fingerprint());
         }
+        *TLS.write().unwrap() = tls;
 
-        unsafe { PORT = local_clip.port.clone() };
+        let headless = match &local_clip.headless {
+            Some(config) => Some(config.clone()),
+            None if !clipboard::available() => {
+                warn!("No system clipboard, running in headless mode");
+                Some(HeadlessConfig::default())
+            }
+            None => None,
+        };
+        if let Some(config) = &headless {
+            clipboard::set_headless(config.clone());
+        }
 
         Uniclip {
+            node_id: local_clip.node_id.clone(),
+            headless: headless.is_some(),
             port: local_clip.port,
+            listen: local_clip.listen.clone(),
             key,
-            hotkey,
+            hotkey: local_clip.hotkey.clone(),
+            peers: local_clip.peers.clone(),
+            discovery: local_clip.discovery.clone(),
         }
     }
 
+    // 在热键线程中调用, 通过运行时把广播交给异步任务
     fn listen_hotkey() {
-        let text = clipboard::get();
-        // message::info(format!("debug: Read clipboard data: {}", text));
-        println!("debug: Read clipboard data: {}", text);
-        let hash = packer::hash(&text);
-        let data = UniclipPayload::Update(hash, text);
-        broadcast(data);
+        if let Some(runtime) = RUNTIME.read().unwrap().as_ref() {
+            runtime.spawn(async {
+                if let Err(error) = push().await {
+                    warn!("{}", error);
+                }
+            });
+        }
     }
 
     fn listen_port(&self) {
-        let key = self.key.clone();
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port));
-
-        thread::spawn(move || {
-            // 判断是否有新的连接
-            for stream in listener.unwrap().incoming() {
-                match stream {
-                    Ok(stream) => {
-                        add_stream(&key, stream);
-                    }
-                    Err(error) => {
-                        message::error(format!("{}", error));
+        let mut listening = 0;
+        for addr in self.listen.iter() {
+            let listener = match bind(*addr) {
+                Ok(listener) => listener,
+                Err(error) => {
+                    error!("{}: {}", addr, error);
+                    continue;
+                }
+            };
+            listening += 1;
+
+            let key = self.key.clone();
+            tokio::spawn(async move {
+                // 判断是否有新的连接
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            let key = key.clone();
+                            tokio::spawn(async move { add_stream(&key, stream).await });
+                        }
+                        Err(error) => {
+                            error!("{}", error);
+                        }
                     }
                 }
-            }
-        });
+            });
+        }
+        if listening == 0 {
+            error!("No listen address available");
+            std::process::exit(-1);
+        }
     }
 
-    pub fn start(&mut self) {
-        let mut hk_manager = HotkeyManager::new();
-        let hk = Hotkey::new(self.hotkey.clone(), Self::listen_hotkey);
-        hk_manager.register(hk);
-        hk_manager.listen();
+    pub async fn start(&mut self) {
+        *RUNTIME.write().unwrap() = Some(Handle::current());
+
+        // 无头模式下没有桌面, 不监听热键
+        if !self.headless {
+            HotkeyManager::from_config(&self.hotkey, Self::listen_hotkey).listen();
+        }
 
         self.listen_port();
+        // 同时连接所有指定的节点, 失败的节点由心跳任务重试
+        *STATIC_PEERS.lock().unwrap() = self.peers.iter().map(|p| (p.clone(), None)).collect();
+        for peer in self.peers.iter() {
+            connect(peer.to_string(), peer.clone());
+        }
+        self.discover().await;
+        tokio::spawn(heartbeat());
+    }
+
+    async fn discover(&self) {
+        let config = match &self.discovery {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let on_found = Arc::new(move |peer_id: String, remote: RemoteClipboard| {
+            if !is_known(&peer_id) {
+                info!("Discovered {} ({})", remote, peer_id);
+                connect(peer_id, remote);
+            }
+        });
+        let service = discovery::Discovery::new(
+            self.key.clone(),
+            self.node_id.clone(),
+            self.port,
+            config.group,
+            config.interface,
+            on_found,
+        );
+        if let Err(error) = service.start().await {
+            error!("Discovery: {}", error);
+        }
     }
 }
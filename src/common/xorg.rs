@@ -0,0 +1,36 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::sync::{Mutex, Once};
+use x11::xlib;
+
+// Xlib 的全局设置, 快捷键和选区的线程共用, 必须在其他 Xlib 调用之前初始化
+
+static INIT: Once = Once::new();
+
+lazy_static! {
+    // 每个连接最近一次的错误码, 以 Display 指针为键
+    static ref ERRORS: Mutex<HashMap<usize, u8>> = Mutex::new(HashMap::new());
+}
+
+// 默认的处理函数会直接退出进程, 这里只记录错误
+unsafe extern "C" fn on_error(display: *mut xlib::Display, event: *mut xlib::XErrorEvent) -> c_int {
+    ERRORS
+        .lock()
+        .unwrap()
+        .insert(display as usize, (*event).error_code);
+    0
+}
+
+// 错误处理函数是进程全局的, 只能设置一次
+pub fn init() {
+    INIT.call_once(|| unsafe {
+        xlib::XInitThreads();
+        xlib::XSetErrorHandler(Some(on_error));
+    });
+}
+
+// 取出并清除连接上的错误, 打开和关闭连接时也应调用以免读到旧的错误
+pub fn take_error(display: *mut xlib::Display) -> Option<u8> {
+    ERRORS.lock().unwrap().remove(&(display as usize))
+}
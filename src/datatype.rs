@@ -1,5 +1,10 @@
+use device_query::Keycode;
 use serde::{Deserialize, Serialize};
 use serde_encrypt::{serialize::impls::BincodeSerializer, traits::SerdeEncryptSharedKey};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RemoteClipboard {
@@ -13,41 +18,351 @@ impl PartialEq for RemoteClipboard {
     }
 }
 
+impl std::fmt::Display for RemoteClipboard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Member {
+    pub node_id: String,
+    pub remote: RemoteClipboard,
+    pub version: u64,
+    pub alive: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    Bidirectional,
+    SendOnly,
+    ReceiveOnly,
+    Disabled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRule {
+    pub target: String,
+    pub mode: SyncMode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeLimit {
+    pub mime: String,         // mime type, e.g. image/png, image/* or *
+    pub size: usize,          // max bytes of each representation
+    pub peer: Option<String>, // node id, host or host:port, every peer if not set
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeAllow {
+    pub mime: String,
+    pub peer: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfirmRule {
+    pub size: usize, // entries larger than this wait for confirmation
+    pub peer: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReceivePolicy {
+    pub limits: Vec<SizeLimit>,
+    pub allow: Vec<TypeAllow>,
+    pub confirm: Vec<ConfirmRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncPolicy {
+    pub default_mode: SyncMode,
+    pub groups: Vec<String>,
+    pub rules: Vec<PeerRule>,
+    pub receive: ReceivePolicy,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub group: SocketAddrV4,
+    pub interface: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerPin {
+    pub node_id: String,
+    pub fingerprint: String, // sha256 of the peer certificate
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub data_dir: PathBuf,
+    pub pins: Vec<PeerPin>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyBackend {
+    Poll, // poll the keyboard state
+    Grab, // register a global shortcut (X11)
+}
+
+#[derive(Debug, Clone)]
+pub struct HotkeyConfig {
+    pub backend: HotkeyBackend,
+    pub keys: Vec<Vec<Keycode>>, // key combinations pressed in order
+    pub interval: u64,           // polling interval in ms
+    pub debounce: u64,           // minimum time between two triggers in ms
+    pub window: u64,             // maximum time of each step of a sequence in ms
+}
+
+#[derive(Debug, Clone)]
+pub struct SecretConfig {
+    pub builtin: bool,            // detect common keys and tokens
+    pub deny: Vec<String>,        // patterns of content that must not be sent
+    pub clear_after: Option<u64>, // send secrets and clear them on peers after seconds
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClearConfig {
+    pub after: Option<u64>, // clear received entries after seconds
+    pub propagate: bool,    // ask peers to clear the same entry
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: usize,          // bytes per second
+    pub peer: Option<String>, // node id, host, host:port or * for each peer, total if not set
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthConfig {
+    pub upload: Vec<RateLimit>,
+    pub download: Vec<RateLimit>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeadlessConfig {
+    pub output: Option<PathBuf>, // file that receives every update
+    pub exec: Option<String>,    // command that receives every update on stdin
+}
+
 pub struct LocalClipboard {
+    pub node_id: String,
+    pub data_dir: PathBuf,
     pub port: u16,
+    pub listen: Vec<SocketAddr>,
     pub password: String,
-    pub peer: RemoteClipboard,
+    pub peers: Vec<RemoteClipboard>,
+    pub policy: SyncPolicy,
+    pub discovery: Option<DiscoveryConfig>,
+    pub relay: bool,
+    pub tls: Option<TlsConfig>,
+    pub headless: Option<HeadlessConfig>,
+    pub hotkey: HotkeyConfig,
+    pub secret: SecretConfig,
+    pub clear: ClearConfig,
+    pub lazy_size: Option<usize>, // only announce entries larger than this, peers fetch them
+    pub bandwidth: BandwidthConfig,
 }
 
 pub const UNICLIP_MAGIC: u16 = ('U' as u16) << 8 | 'C' as u16;
-pub const UNICLIP_PROTO_VERSION: u8 = 1;
-pub const UNICLIP_DATA_LIMIT: usize = 5 * 1024;
+pub const UNICLIP_PROTO_VERSION: u8 = 19;
+pub const UNICLIP_FRAME_LIMIT: usize = 64 * 1024 * 1024;
+// 超过该大小的条目分块发送
+pub const UNICLIP_BIG_LIMIT: usize = 1024 * 1024;
+pub const UNICLIP_CHUNK_SIZE: usize = 256 * 1024;
+pub const UNICLIP_MAX_HOPS: u8 = 8;
+
+pub const MIME_TEXT: &str = "text/plain";
+pub const MIME_HTML: &str = "text/html";
+pub const MIME_RTF: &str = "text/rtf";
+pub const MIME_PNG: &str = "image/png";
+pub const MIME_FILE: &str = "application/octet-stream";
+// 密码管理器复制密码时设置的标记, 内容为 "secret"
+pub const MIME_PASSWORD_HINT: &str = "x-kde-passwordManagerHint";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UniclipRoute {
+    pub id: String,     // message id
+    pub origin: String, // origin node id
+    pub hops: u8,       // forwarded times
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClipboardEntry {
+    pub id: String,                      // entry id
+    pub origin: String,                  // node id of the node it was copied on
+    pub time: u64,                       // copied time, ms since the unix epoch
+    pub name: Option<String>,            // file name if the entry is a file
+    pub formats: Vec<(String, Vec<u8>)>, // mime type, content of each representation
+    pub sensitive: bool,                 // passwords, keys and other secrets
+    pub clear_after: Option<u64>,        // seconds after which receivers clear it
+}
+
+impl ClipboardEntry {
+    pub fn new(origin: &str, formats: Vec<(String, Vec<u8>)>) -> ClipboardEntry {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        ClipboardEntry {
+            id: Uuid::new_v4().to_string(),
+            origin: origin.to_string(),
+            time,
+            name: None,
+            formats,
+            sensitive: false,
+            clear_after: None,
+        }
+    }
+
+    pub fn from_text(origin: &str, text: String) -> ClipboardEntry {
+        Self::new(origin, vec![(MIME_TEXT.to_string(), text.into_bytes())])
+    }
+
+    pub fn from_file(origin: &str, name: &str, data: Vec<u8>) -> ClipboardEntry {
+        ClipboardEntry {
+            name: Some(name.to_string()),
+            ..Self::new(origin, vec![(MIME_FILE.to_string(), data)])
+        }
+    }
+
+    pub fn get(&self, mime: &str) -> Option<&[u8]> {
+        self.formats
+            .iter()
+            .find(|(m, _)| m == mime)
+            .map(|(_, data)| &data[..])
+    }
+
+    // 第一种格式, 只支持一种格式的地方使用
+    pub fn mime(&self) -> &str {
+        self.formats
+            .first()
+            .map(|(mime, _)| &mime[..])
+            .unwrap_or(MIME_TEXT)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.formats
+            .first()
+            .map(|(_, data)| &data[..])
+            .unwrap_or_default()
+    }
+
+    pub fn info(&self) -> EntryInfo {
+        EntryInfo {
+            id: self.id.clone(),
+            origin: self.origin.clone(),
+            time: self.time,
+            name: self.name.clone(),
+            formats: self
+                .formats
+                .iter()
+                .map(|(mime, data)| (mime.clone(), data.len()))
+                .collect(),
+            sensitive: self.sensitive,
+            clear_after: self.clear_after,
+        }
+    }
+
+    // 由条目信息和各格式的内容还原条目
+    pub fn from_info(info: EntryInfo, contents: Vec<Vec<u8>>) -> ClipboardEntry {
+        ClipboardEntry {
+            id: info.id,
+            origin: info.origin,
+            time: info.time,
+            name: info.name,
+            formats: info
+                .formats
+                .into_iter()
+                .map(|(mime, _)| mime)
+                .zip(contents)
+                .collect(),
+            sensitive: info.sensitive,
+            clear_after: info.clear_after,
+        }
+    }
+
+    // 分块发送的内容, 每种格式分别分块
+    pub fn chunks(&self) -> Vec<&[u8]> {
+        self.formats
+            .iter()
+            .flat_map(|(_, data)| data.chunks(UNICLIP_CHUNK_SIZE))
+            .collect()
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.name.is_none() && self.mime().starts_with("image/")
+    }
 
+    pub fn size(&self) -> usize {
+        self.formats.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
+// 不含内容的条目信息
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum UniclipBig {
-    Text,
-    Picture,
-    File,
+pub struct EntryInfo {
+    pub id: String,
+    pub origin: String,
+    pub time: u64,
+    pub name: Option<String>,
+    pub formats: Vec<(String, usize)>, // mime type, size
+    pub sensitive: bool,
+    pub clear_after: Option<u64>,
 }
 
+impl EntryInfo {
+    pub fn size(&self) -> usize {
+        self.formats.iter().map(|(_, size)| size).sum()
+    }
+
+    // 每个分块的长度, 与 ClipboardEntry::chunks 一致
+    pub fn chunks(&self) -> Vec<usize> {
+        let mut chunks = Vec::new();
+        for (_, size) in self.formats.iter() {
+            let full = size / UNICLIP_CHUNK_SIZE;
+            chunks.extend(std::iter::repeat_n(UNICLIP_CHUNK_SIZE, full));
+            if size % UNICLIP_CHUNK_SIZE > 0 {
+                chunks.push(size % UNICLIP_CHUNK_SIZE);
+            }
+        }
+        chunks
+    }
+}
+
+// 新的变体只加在末尾; 修改已有变体或其中的类型时递增 UNICLIP_PROTO_VERSION
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum UniclipPayload {
     Echo(u32),    // random number A
     EchoRes(u32), // A + 1
 
-    Peer(u32),                           // random number A
-    PeerList(u32, Vec<RemoteClipboard>), // A + 1, peers
+    Gossip(Vec<Member>), // membership changes
+
+    Hello(u32, String, u16),    // random number A, node id, port
+    HelloRes(u32, String, u16), // A + 1, node id, port
 
-    Port(u32),         // random number A
-    PortRes(u32, u16), // A + 1, port
+    Update(UniclipRoute, String, ClipboardEntry), // route, data hash, entry
+    UpdateRes(usize),                             // received data length
+    Pull,                                         // request the current clipboard
+    Clear(String),                                // hash of the entry to clear
+    Fetch(String),                                // data hash of an offered entry
+    FetchRes(String, bool),                       // data hash, available
 
-    Update(String, String), // data hash, data
-    UpdateRes(usize),       // received data length
+    // route, data hash, entry info, preview, content is sent on Fetch
+    Offer(UniclipRoute, String, EntryInfo, String),
 
-    UpdateBig(String, UniclipBig, u32), // data hash, data type, data frame size
-    UpdateBigAck(String, u32),          // data hash, data frame size
-    UpdateBigData(Vec<u8>),             // data
-    UpdateBigFinish(usize),             // data length
+    // route, data hash, entry info, hash of each chunk of the representations
+    UpdateBig(UniclipRoute, String, EntryInfo, Vec<String>),
+    UpdateBigAck(String, bool, Vec<usize>), // message id, accepted, indexes of the chunks to send
+    UpdateBigData(String, usize, Vec<u8>),  // message id, chunk index, data
+    UpdateBigFinish(String),                // message id
+    UpdateBigCancel(String),                // message id, sent by either side
+
+    Groups(Vec<String>), // sync groups of the sender
+    Throttle(usize),     // download rate limit of the sender in bytes per second
 
     Quit(u32),    // A
     QuitRes(u32), // A + 1
@@ -62,16 +377,23 @@ pub mod payload_type {
     lazy_static! {
         pub static ref ECHO: String = "Echo".to_string();
         pub static ref ECHO_RES: String = "EchoRes".to_string();
-        pub static ref PEER: String = "Peer".to_string();
-        pub static ref PEER_LIST: String = "PeerList".to_string();
-        pub static ref PORT: String = "Port".to_string();
-        pub static ref PORT_RES: String = "PortRes".to_string();
+        pub static ref GOSSIP: String = "Gossip".to_string();
+        pub static ref HELLO: String = "Hello".to_string();
+        pub static ref HELLO_RES: String = "HelloRes".to_string();
         pub static ref UPDATE: String = "Update".to_string();
         pub static ref UPDATE_RES: String = "UpdateRes".to_string();
+        pub static ref PULL: String = "Pull".to_string();
+        pub static ref CLEAR: String = "Clear".to_string();
+        pub static ref OFFER: String = "Offer".to_string();
+        pub static ref FETCH: String = "Fetch".to_string();
+        pub static ref FETCH_RES: String = "FetchRes".to_string();
         pub static ref UPDATE_BIG: String = "UpdateBig".to_string();
         pub static ref UPDATE_BIG_ACK: String = "UpdateBigAck".to_string();
         pub static ref UPDATE_BIG_DATA: String = "UpdateBigData".to_string();
         pub static ref UPDATE_BIG_FINISH: String = "UpdateBigFinish".to_string();
+        pub static ref UPDATE_BIG_CANCEL: String = "UpdateBigCancel".to_string();
+        pub static ref GROUPS: String = "Groups".to_string();
+        pub static ref THROTTLE: String = "Throttle".to_string();
         pub static ref QUIT: String = "Quit".to_string();
         pub static ref QUIT_RES: String = "QuitRes".to_string();
         pub static ref SHUT_DOWN: String = "ShutDown".to_string();
@@ -89,3 +411,42 @@ pub struct UniclipDataFrame {
 impl SerdeEncryptSharedKey for UniclipDataFrame {
     type S = BincodeSerializer<Self>;
 }
+
+// 数据帧的开头部分, 协议版本不同时负载可能无法解析, 只读取版本号
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UniclipFrameHeader {
+    pub magic: u16,
+    pub version: u8,
+}
+
+impl SerdeEncryptSharedKey for UniclipFrameHeader {
+    type S = BincodeSerializer<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_follow_representations() {
+        let entry = ClipboardEntry::new(
+            "node",
+            vec![
+                (MIME_TEXT.to_string(), vec![1; UNICLIP_CHUNK_SIZE + 10]),
+                (MIME_HTML.to_string(), Vec::new()),
+                (MIME_PNG.to_string(), vec![2; UNICLIP_CHUNK_SIZE]),
+            ],
+        );
+        let chunks: Vec<usize> = entry.chunks().iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(chunks, vec![UNICLIP_CHUNK_SIZE, 10, UNICLIP_CHUNK_SIZE]);
+        assert_eq!(entry.info().chunks(), chunks);
+        // 相同的内容在其他条目中分块相同
+        let other = ClipboardEntry::from_file("node", "a.png", vec![2; UNICLIP_CHUNK_SIZE]);
+        assert_eq!(other.chunks()[0], entry.chunks()[2]);
+
+        let contents = entry.formats.iter().map(|(_, data)| data.clone()).collect();
+        let rebuilt = ClipboardEntry::from_info(entry.info(), contents);
+        assert_eq!(rebuilt.id, entry.id);
+        assert_eq!(rebuilt.formats, entry.formats);
+    }
+}
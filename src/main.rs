@@ -1,10 +1,32 @@
 mod common;
 mod datatype;
 
-use clap::Parser;
-use common::hotkey::Keycode;
-use common::{message, uniclip};
-use datatype::RemoteClipboard;
+use clap::{Parser, Subcommand};
+#[cfg(unix)]
+use common::control;
+use common::discovery::DISCOVERY_ADDR;
+use common::hotkey;
+use common::{address, identity, logging, policy, secret, uniclip};
+use datatype::{
+    BandwidthConfig, ClearConfig, ConfirmRule, DiscoveryConfig, HeadlessConfig, HotkeyBackend,
+    HotkeyConfig, PeerPin, PeerRule, RateLimit, ReceivePolicy, RemoteClipboard, SecretConfig,
+    SizeLimit, SyncMode, SyncPolicy, TlsConfig, TypeAllow,
+};
+#[cfg(unix)]
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+const DEFAULT_PASSWORD: &str = "nopassword";
+
+// 启动时输出到 stderr, stdout 留给 recv 等命令的输出
+const BANNER: &str = r"             _   ___ _ _       _                         _
+ /\ /\ _ __ (_) / __\ (_)_ __ | |__   ___   __ _ _ __ __| |
+/ / \ \ '_ \| |/ /  | | | '_ \| '_ \ / _ \ / _` | '__/ _` |
+\ \_/ / | | | / /___| | | |_) | |_) | (_) | (_| | | | (_| |
+ \___/|_| |_|_\____/|_|_| .__/|_.__/ \___/ \__,_|_|  \__,_|
+                        |_|                                    ";
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about = None, long_about = None)]
@@ -13,71 +35,501 @@ struct Args {
     #[clap(short = 'p', long, value_parser, default_value_t = 10500)]
     port: u16,
 
+    /// Listen address, e.g. 0.0.0.0, [::]:10500, can be repeated
+    #[clap(short, long, value_parser)]
+    listen: Vec<String>,
+
     /// Password
-    #[clap(short='P', long, value_parser, default_value_t = String::from("nopassword"))]
-    password: String,
+    #[clap(short = 'P', long, value_parser)]
+    password: Option<String>,
+
+    /// Remote host, e.g. host, host:port, [::1]:port, can be repeated or comma separated
+    #[clap(short, long, value_parser, value_delimiter = ',')]
+    remote: Vec<String>,
+
+    /// Sync group, can be repeated
+    #[clap(short, long = "group", value_parser)]
+    groups: Vec<String>,
+
+    /// Per-peer sync mode, <host[:port]>=<both|send|recv|off>
+    #[clap(short, long = "sync", value_parser)]
+    sync: Vec<PeerRule>,
+
+    /// Default sync mode for peers without a rule
+    #[clap(long, value_parser, default_value = "both")]
+    sync_default: SyncMode,
+
+    /// Max size of received content, <mime>=<size>[@<peer>], e.g. image/*=10M, can be repeated
+    #[clap(long = "max-size", value_parser)]
+    max_sizes: Vec<SizeLimit>,
+
+    /// Only receive these content types, <mime>[@<peer>], e.g. text/*, can be repeated
+    #[clap(long = "allow-type", value_parser)]
+    allow_types: Vec<TypeAllow>,
+
+    /// Ask before receiving entries larger than <size>[@<peer>], e.g. 100M
+    #[clap(long = "confirm-size", value_parser)]
+    confirm_sizes: Vec<ConfirmRule>,
+
+    /// Never send content matching this regex, can be repeated
+    #[clap(long = "deny", value_parser)]
+    deny: Vec<String>,
+
+    /// Do not detect common keys, tokens and passwords
+    #[clap(long, value_parser)]
+    no_builtin_patterns: bool,
+
+    /// Send secrets anyway and clear them on peers after this many seconds
+    #[clap(long, value_parser)]
+    sync_secrets: Option<u64>,
+
+    /// Clear received content from the clipboard after this many seconds
+    #[clap(long, value_parser)]
+    clear_after: Option<u64>,
+
+    /// Also clear the content on peers when it is cleared here
+    #[clap(long, value_parser)]
+    clear_propagate: bool,
+
+    /// Only announce entries larger than <size> to peers, they fetch the content when pasting
+    #[clap(long, value_parser = policy::parse_size)]
+    lazy_size: Option<usize>,
+
+    /// Upload rate limit, <rate>[@<peer>], e.g. 1M for all peers in total, 256K@* for each peer,
+    /// can be repeated
+    #[clap(long = "upload-limit", value_parser)]
+    upload_limits: Vec<RateLimit>,
+
+    /// Download rate limit, <rate>[@<peer>], same format as --upload-limit
+    #[clap(long = "download-limit", value_parser)]
+    download_limits: Vec<RateLimit>,
+
+    /// Directory for the node id and other local state
+    #[clap(long, value_parser)]
+    data_dir: Option<PathBuf>,
+
+    /// Relay updates between peers that are not directly connected
+    #[clap(long, value_parser)]
+    relay: bool,
+
+    /// Use TLS for peer connections, every peer must enable it too
+    #[clap(long, value_parser)]
+    tls: bool,
+
+    /// Pinned certificate of a peer, <node id>=<sha256>, can be repeated
+    #[clap(long = "tls-pin", value_parser)]
+    tls_pins: Vec<PeerPin>,
+
+    /// Hotkey, keys pressed together are joined by '+', steps of a sequence by ',',
+    /// e.g. LControl+LShift+C or LControl,LControl
+    #[clap(long, value_parser, default_value = "LControl+LShift+C")]
+    hotkey: String,
+
+    /// How to listen for the hotkey, poll or grab (X11 global shortcut)
+    #[clap(long, value_parser, default_value = "poll")]
+    hotkey_backend: HotkeyBackend,
 
-    /// Remote host
-    #[clap(short, long, value_parser, default_value_t = String::from("nopeer"))]
-    remote: String,
+    /// Hotkey polling interval in milliseconds
+    #[clap(long, value_parser, default_value_t = 30)]
+    hotkey_interval: u64,
+
+    /// Minimum time between two hotkey triggers in milliseconds
+    #[clap(long, value_parser, default_value_t = 300)]
+    hotkey_debounce: u64,
+
+    /// Maximum time of each step of a hotkey sequence in milliseconds
+    #[clap(long, value_parser, default_value_t = 400)]
+    hotkey_window: u64,
+
+    /// Run without the system clipboard, keep received updates in memory
+    #[clap(long, value_parser)]
+    headless: bool,
+
+    /// Headless mode: write every received update to this file
+    #[clap(long, value_parser)]
+    output: Option<PathBuf>,
+
+    /// Headless mode: pipe every received update to this command
+    #[clap(long, value_parser)]
+    exec: Option<String>,
+
+    /// Log level or filter, e.g. info, debug, uni_clipboard=trace
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: String,
+
+    /// Also write logs as JSON lines to this file
+    #[clap(long, value_parser)]
+    log_file: Option<PathBuf>,
+
+    /// Include clipboard contents in logs
+    #[clap(long, value_parser)]
+    log_sensitive: bool,
+
+    /// Control socket of the running node, default <data dir>/control.sock
+    #[clap(long, value_parser)]
+    control: Option<PathBuf>,
+
+    /// Discover peers on the LAN
+    #[clap(short, long, value_parser)]
+    discover: bool,
+
+    /// Multicast or broadcast address used for discovery
+    #[clap(long, value_parser, default_value = DISCOVERY_ADDR)]
+    discovery_addr: SocketAddrV4,
+
+    /// Local interface address used for multicast discovery
+    #[clap(long, value_parser, default_value = "0.0.0.0")]
+    discovery_interface: Ipv4Addr,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Commands sent to the running node through the control socket
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the status of the running node
+    Status,
+    /// List connected peers
+    Peers,
+    /// Send the local clipboard to peers
+    Push,
+    /// Ask peers to send their clipboard
+    Pull,
+    /// Show recent clipboard updates
+    History,
+    /// Send content from a file or stdin to peers
+    Send {
+        /// File to send, read from stdin if not set
+        #[clap(value_parser)]
+        path: Option<PathBuf>,
+        /// Content type, detected from the content if not set
+        #[clap(long = "type", value_parser = ["text", "image", "file"])]
+        kind: Option<String>,
+        /// Mark the content as a secret, also set by CLIPBOARD_STATE=secret
+        #[clap(long, value_parser)]
+        secret: bool,
+    },
+    /// Wait for the next update from peers and write it to stdout
+    Recv,
+    /// Write the current clipboard to stdout, fetch the last offered update first
+    Get,
+    /// Connect to a peer, e.g. host, host:port, [::1]:port
+    Connect { remote: String },
+    /// Disconnect from a peer by node id, host or host:port
+    Disconnect { target: String },
+    /// List updates waiting for confirmation
+    Pending,
+    /// Receive an update waiting for confirmation
+    Accept { id: String },
+    /// Reject an update waiting for confirmation
+    Reject { id: String },
+    /// List updates offered by peers without their content
+    Offers,
+    /// Fetch an offered update into the clipboard, the last one if no id is given
+    Fetch { id: Option<String> },
+    /// Show chunked transfers in progress
+    Transfers,
+    /// Cancel a chunked transfer on both ends
+    Cancel { id: String },
+    /// Stop the running node
+    Quit,
 }
 
-fn init_local_clipboard(args: Args) -> datatype::LocalClipboard {
-    if args.password == String::from("nopassword") {
-        message::warning("Use the default password, which may be a security risk.".to_string());
+impl Command {
+    fn words(&self) -> Vec<String> {
+        match self {
+            Command::Status => vec!["status".to_string()],
+            Command::Peers => vec!["peers".to_string()],
+            Command::Push => vec!["push".to_string()],
+            Command::Pull => vec!["pull".to_string()],
+            Command::History => vec!["history".to_string()],
+            Command::Send { .. } => vec!["send".to_string()],
+            Command::Recv => vec!["recv".to_string()],
+            Command::Get => vec!["get".to_string()],
+            Command::Connect { remote } => vec!["connect".to_string(), remote.clone()],
+            Command::Disconnect { target } => vec!["disconnect".to_string(), target.clone()],
+            Command::Pending => vec!["pending".to_string()],
+            Command::Accept { id } => vec!["accept".to_string(), id.clone()],
+            Command::Reject { id } => vec!["reject".to_string(), id.clone()],
+            Command::Offers => vec!["offers".to_string()],
+            Command::Fetch { id } => ["fetch".to_string()]
+                .into_iter()
+                .chain(id.clone())
+                .collect(),
+            Command::Transfers => vec!["transfers".to_string()],
+            Command::Cancel { id } => vec!["cancel".to_string(), id.clone()],
+            Command::Quit => vec!["quit".to_string()],
+        }
     }
+}
 
-    let mut peer = datatype::RemoteClipboard {
-        host: "".to_string(),
-        port: 0,
+// 密码管理器等程序通过 CLIPBOARD_STATE 标记敏感内容
+#[cfg(unix)]
+fn secret_state() -> bool {
+    matches!(
+        std::env::var("CLIPBOARD_STATE").as_deref(),
+        Ok("secret") | Ok("sensitive")
+    )
+}
+
+// send 命令读取内容并在命令后附加类型, 长度, 是否敏感和文件名
+#[cfg(unix)]
+fn read_content(command: &Command) -> (Vec<String>, Vec<u8>) {
+    let (path, kind, secret) = match command {
+        Command::Send { path, kind, secret } => (path, kind, *secret || secret_state()),
+        _ => return (command.words(), Vec::new()),
+    };
+    let res = match path {
+        Some(path) => std::fs::read(path),
+        None => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data).map(|_| data)
+        }
     };
-    if args.remote.ne("nopeer") {
-        let words: Vec<&str> = args.remote.split(":").collect();
-        if words.len() > 2 {
-            message::error(format!("Invalid remote host \"{}\"", args.remote));
+    let data = match res {
+        Ok(data) => data,
+        Err(error) => {
+            error!("{}", error);
             std::process::exit(-1);
-        } else {
-            if words.len() == 2 {
-                peer.host = words[0].to_string();
-                peer.port = match words[1].parse::<u16>() {
-                    Ok(port) => port,
-                    Err(_) => {
-                        message::error(format!("Invalid remote port \"{}\"", words[1]));
-                        std::process::exit(-1);
-                    }
-                };
-            } else {
-                message::warning("The remote port is not set, use the local port.".to_string());
-                peer.host = words[0].to_string();
-                peer.port = args.port;
+        }
+    };
+    let kind = match kind {
+        Some(kind) => kind.clone(),
+        None => control::detect(&data).to_string(),
+    };
+    let name = path
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "stdin".to_string());
+    let mut words = command.words();
+    let secret = if secret { "1" } else { "0" };
+    words.extend([kind, data.len().to_string(), secret.to_string(), name]);
+    (words, data)
+}
+
+#[cfg(unix)]
+fn run_command(path: &Path, command: &Command) -> ! {
+    let (words, body) = read_content(command);
+    match control::request(path, &words, &body) {
+        Ok(output) => {
+            let _ = std::io::stdout().write_all(&output);
+            std::process::exit(0);
+        }
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(-1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn run_command(_path: &Path, _command: &Command) -> ! {
+    error!("The control socket is only supported on Unix");
+    std::process::exit(-1);
+}
+
+#[cfg(unix)]
+async fn start_control(path: &Path, port: u16) -> bool {
+    match control::start(path, port).await {
+        Ok(_) => true,
+        Err(error) => {
+            warn!("Control socket: {}", error);
+            false
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn start_control(_path: &Path, _port: u16) -> bool {
+    false
+}
+
+fn init_local_clipboard(args: Args, data_dir: PathBuf) -> datatype::LocalClipboard {
+    let password = match args.password {
+        Some(password) => password,
+        None => {
+            warn!("Use the default password, which may be a security risk.");
+            DEFAULT_PASSWORD.to_string()
+        }
+    };
+
+    let mut peers = Vec::new();
+    for remote in args.remote.iter() {
+        let (host, port) = match address::parse_remote(remote) {
+            Ok(res) => res,
+            Err(error) => {
+                error!("Invalid remote host \"{}\": {}", remote, error);
+                std::process::exit(-1);
+            }
+        };
+        let port = match port {
+            Some(port) => port,
+            None => {
+                warn!(
+                    "The remote port of \"{}\" is not set, use the local port.",
+                    remote
+                );
+                args.port
+            }
+        };
+        peers.push(RemoteClipboard { host, port });
+    }
+
+    let mut listen = Vec::new();
+    for addr in args.listen.iter() {
+        match address::parse_listen(addr, args.port) {
+            Ok(addr) => listen.push(addr),
+            Err(error) => {
+                error!("{}", error);
+                std::process::exit(-1);
             }
         }
     }
+    if listen.is_empty() {
+        listen.push(SocketAddr::from((Ipv4Addr::UNSPECIFIED, args.port)));
+    }
+
+    let discovery = if args.discover {
+        Some(DiscoveryConfig {
+            group: args.discovery_addr,
+            interface: args.discovery_interface,
+        })
+    } else {
+        None
+    };
+
+    let tls = if args.tls {
+        Some(TlsConfig {
+            data_dir: data_dir.clone(),
+            pins: args.tls_pins,
+        })
+    } else {
+        None
+    };
+
+    let hotkey = match hotkey::parse_keys(&args.hotkey) {
+        Ok(keys) => HotkeyConfig {
+            backend: args.hotkey_backend,
+            keys,
+            interval: args.hotkey_interval,
+            debounce: args.hotkey_debounce,
+            window: args.hotkey_window,
+        },
+        Err(error) => {
+            error!("{}", error);
+            std::process::exit(-1);
+        }
+    };
+
+    let secret = SecretConfig {
+        builtin: !args.no_builtin_patterns,
+        deny: args.deny,
+        clear_after: args.sync_secrets,
+    };
+
+    // 指定输出文件或命令时也使用无头模式
+    let headless = if args.headless || args.output.is_some() || args.exec.is_some() {
+        Some(HeadlessConfig {
+            output: args.output,
+            exec: args.exec,
+        })
+    } else {
+        None
+    };
 
     datatype::LocalClipboard {
-        port: args.port,
-        password: args.password,
-        peer: RemoteClipboard {
-            host: peer.host,
-            port: peer.port,
+        node_id: identity::load_node_id(&data_dir),
+        data_dir,
+        // 向其他节点公布第一个监听地址的端口
+        port: listen[0].port(),
+        listen,
+        password,
+        peers,
+        policy: SyncPolicy {
+            default_mode: args.sync_default,
+            groups: args.groups,
+            rules: args.sync,
+            receive: ReceivePolicy {
+                limits: args.max_sizes,
+                allow: args.allow_types,
+                confirm: args.confirm_sizes,
+            },
+        },
+        discovery,
+        relay: args.relay,
+        tls,
+        headless,
+        hotkey,
+        secret,
+        clear: ClearConfig {
+            after: args.clear_after,
+            propagate: args.clear_propagate,
+        },
+        lazy_size: args.lazy_size,
+        bandwidth: BandwidthConfig {
+            upload: args.upload_limits,
+            download: args.download_limits,
         },
     }
 }
 
-fn main() {
-    message::welcome();
+#[tokio::main]
+async fn main() {
     let args = Args::parse();
-    let local_clipboard = init_local_clipboard(args);
+    if let Err(error) = logging::init(
+        &args.log_level,
+        args.log_file.as_deref(),
+        args.log_sensitive,
+    ) {
+        eprintln!("error: {}", error);
+        std::process::exit(-1);
+    }
+    let data_dir = args
+        .data_dir
+        .clone()
+        .unwrap_or_else(identity::default_data_dir);
+    let control_path = args
+        .control
+        .clone()
+        .unwrap_or_else(|| data_dir.join("control.sock"));
+    if let Some(command) = &args.command {
+        run_command(&control_path, command);
+    }
+
+    eprintln!("{}", BANNER);
+    // 快捷键, 选区等线程同时使用 Xlib, 需要在任何 Xlib 调用之前初始化
+    #[cfg(target_os = "linux")]
+    common::xorg::init();
+    let local_clipboard = init_local_clipboard(args, data_dir);
 
+    if let Err(error) = secret::init(&local_clipboard.secret) {
+        error!("{}", error);
+        std::process::exit(-1);
+    }
     uniclip::init();
-    let mut uniclip = uniclip::Uniclip::new(
-        &local_clipboard,
-        vec![Keycode::LControl, Keycode::LShift, Keycode::C],
-    );
-    uniclip.start();
+    let mut uniclip = uniclip::Uniclip::new(&local_clipboard);
+    uniclip.start().await;
 
+    let listen: Vec<String> = local_clipboard
+        .listen
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect();
+    info!("UniClipboard is running on {}.", listen.join(", "));
 
-    message::success("Running".to_string(), format!("UniClipboard is running on port {}.", local_clipboard.port));
+    let control = start_control(&control_path, local_clipboard.port).await;
 
-    loop {}
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = uniclip::wait_shutdown() => (),
+    }
+    uniclip::shutdown().await;
+    #[cfg(unix)]
+    if control {
+        control::remove(&control_path);
+    }
+    #[cfg(not(unix))]
+    let _ = control;
 }